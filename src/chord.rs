@@ -0,0 +1,467 @@
+use evdev::{
+    InputEventKind,
+    InputEvent,
+    EventType,
+    Key,
+};
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+use crate::config::ReleaseMode;
+
+// a set of keys that fires once when all of them are held down together
+pub struct Chord {
+    keys: Vec<Key>,
+    pressed: HashSet<u16>,
+    fired: bool,
+}
+
+impl Chord {
+    pub fn new(keys: Vec<Key>) -> Chord {
+        Chord {
+            keys,
+            pressed: HashSet::new(),
+            fired: false,
+        }
+    }
+
+    // true exactly once per press of the whole chord
+    pub fn update(&mut self, ev: &InputEvent) -> bool {
+        let InputEventKind::Key(key) = ev.kind() else { return false };
+        if !self.keys.contains(&key) {
+            return false;
+        }
+
+        match ev.value() {
+            0 => { self.pressed.remove(&key.0); },
+            1 => { self.pressed.insert(key.0); },
+            _ => (), // autorepeat
+        }
+
+        let complete = self.is_held();
+        let fire = complete && !self.fired;
+        self.fired = complete;
+        fire
+    }
+
+    pub fn is_held(&self) -> bool {
+        !self.keys.is_empty() && self.pressed.len() == self.keys.len()
+    }
+}
+
+// gives the device back to the system, either while the chord is held or until it's pressed again
+pub struct ReleaseChord {
+    chord: Chord,
+    mode: ReleaseMode,
+    released: bool,
+    // chord keys still down from grabbing the device again, they were never pressed as far as the pad knows
+    swallowed: HashSet<u16>,
+}
+
+impl ReleaseChord {
+    pub fn new(keys: Vec<Key>, mode: ReleaseMode) -> ReleaseChord {
+        ReleaseChord {
+            chord: Chord::new(keys),
+            mode,
+            released: false,
+            swallowed: HashSet::new(),
+        }
+    }
+
+    // the new state whenever it changes, true meaning the device should be released
+    pub fn update(&mut self, ev: &InputEvent) -> Option<bool> {
+        let fired = self.chord.update(ev);
+        let released = match self.mode {
+            ReleaseMode::Toggle => self.released ^ fired,
+            ReleaseMode::Hold => self.chord.is_held(),
+        };
+
+        if released == self.released {
+            return None;
+        }
+        self.released = released;
+        if !released {
+            self.swallowed = self.chord.pressed.clone();
+        }
+        Some(released)
+    }
+
+    // whether `ev` is one of the chord keys left over from grabbing again, until it's released
+    pub fn swallows(&mut self, ev: &InputEvent) -> bool {
+        let InputEventKind::Key(key) = ev.kind() else { return false };
+        match ev.value() {
+            0 => self.swallowed.remove(&key.0),
+            _ => self.swallowed.contains(&key.0),
+        }
+    }
+}
+
+// keys pressed one after another within a time window, tapping another key when complete
+pub struct Sequence {
+    keys: Vec<Key>,
+    output: Key,
+    window: Duration,
+    // hold back the keys of the sequence until it's clear whether it's being typed
+    swallow: bool,
+    progress: usize,
+    started: Instant,
+    buffered: Vec<InputEvent>,
+    // keys whose press went into a completed sequence, their release has to go too
+    swallowed: HashSet<u16>,
+}
+
+impl Sequence {
+    pub fn new(keys: Vec<Key>, output: Key, window: Duration, swallow: bool) -> Sequence {
+        Sequence {
+            keys,
+            output,
+            window,
+            swallow,
+            progress: 0,
+            started: Instant::now(),
+            buffered: Vec::new(),
+            swallowed: HashSet::new(),
+        }
+    }
+
+    // what to send on in place of `ev`
+    pub fn feed(&mut self, ev: InputEvent, now: Instant) -> Vec<InputEvent> {
+        let mut out = self.poll(now);
+        let InputEventKind::Key(key) = ev.kind() else {
+            out.push(ev);
+            return out;
+        };
+
+        match ev.value() {
+            1 => {
+                if self.keys.get(self.progress) != Some(&key) {
+                    out.append(&mut self.reset());
+                }
+                if self.keys.get(self.progress) != Some(&key) {
+                    out.push(ev);
+                    return out;
+                }
+
+                if self.progress == 0 {
+                    self.started = now;
+                }
+                self.progress += 1;
+                if self.swallow {
+                    self.buffered.push(ev);
+                } else {
+                    out.push(ev);
+                }
+
+                if self.progress == self.keys.len() {
+                    self.progress = 0;
+                    // whatever is still held down from the sequence gets its release swallowed too
+                    for ev in self.buffered.drain(..) {
+                        if ev.value() == 0 {
+                            self.swallowed.remove(&ev.code());
+                        } else {
+                            self.swallowed.insert(ev.code());
+                        }
+                    }
+                    out.push(InputEvent::new(EventType::KEY, self.output.0, 1));
+                    out.push(InputEvent::new(EventType::KEY, self.output.0, 0));
+                }
+            },
+            0 if self.swallowed.remove(&key.0) => (),
+            0 if self.swallow && self.progress > 0 && self.keys.contains(&key) => self.buffered.push(ev),
+            _ => out.push(ev),
+        }
+        out
+    }
+
+    // gives back held back keys once the window ran out
+    pub fn poll(&mut self, now: Instant) -> Vec<InputEvent> {
+        if self.progress > 0 && now.duration_since(self.started) > self.window {
+            self.reset()
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        match self.progress {
+            0 => None,
+            _ => Some((self.started + self.window).saturating_duration_since(now)),
+        }
+    }
+
+    fn reset(&mut self) -> Vec<InputEvent> {
+        self.progress = 0;
+        self.buffered.drain(..).collect()
+    }
+}
+
+// keys pressed together within a time window that act as another key for as long as they're held,
+// each on its own still goes out as usual, just up to a window late
+pub struct Combo {
+    keys: Vec<Key>,
+    output: Key,
+    window: Duration,
+    started: Instant,
+    // presses held back until it's clear whether they're part of the combo
+    buffered: Vec<InputEvent>,
+    active: bool,
+    // keys whose press went into the combo, their release has to go too
+    swallowed: HashSet<u16>,
+}
+
+impl Combo {
+    pub fn new(keys: Vec<Key>, output: Key, window: Duration) -> Combo {
+        Combo {
+            keys,
+            output,
+            window,
+            started: Instant::now(),
+            buffered: Vec::new(),
+            active: false,
+            swallowed: HashSet::new(),
+        }
+    }
+
+    // what to send on in place of `ev`
+    pub fn feed(&mut self, ev: InputEvent, now: Instant) -> Vec<InputEvent> {
+        let mut out = self.poll(now);
+        let key = match ev.kind() {
+            InputEventKind::Key(key) if self.keys.contains(&key) => key,
+            _ => {
+                out.push(ev);
+                return out;
+            },
+        };
+
+        match ev.value() {
+            1 if self.active => {
+                self.swallowed.insert(key.0);
+            },
+            1 => {
+                if self.buffered.is_empty() {
+                    self.started = now;
+                }
+                self.buffered.push(ev);
+                if self.keys.iter().all(|key| self.buffered.iter().any(|ev| ev.code() == key.0)) {
+                    self.active = true;
+                    self.swallowed.extend(self.buffered.drain(..).map(|ev| ev.code()));
+                    out.push(InputEvent::new(EventType::KEY, self.output.0, 1));
+                }
+            },
+            // letting go of any of them ends it, the rest are swallowed until they're up too
+            0 if self.swallowed.remove(&key.0) => if self.active {
+                self.active = false;
+                out.push(InputEvent::new(EventType::KEY, self.output.0, 0));
+            },
+            // tapped on its own, it goes out in order with whatever was held back before it
+            0 if self.buffered.iter().any(|held| held.code() == key.0) => {
+                out.append(&mut self.buffered);
+                out.push(ev);
+            },
+            _ => out.push(ev),
+        }
+        out
+    }
+
+    // gives back held back presses once the window ran out
+    pub fn poll(&mut self, now: Instant) -> Vec<InputEvent> {
+        if !self.buffered.is_empty() && now.duration_since(self.started) > self.window {
+            self.buffered.drain(..).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        match self.buffered.is_empty() {
+            true => None,
+            false => Some((self.started + self.window).saturating_duration_since(now)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.0, value)
+    }
+
+    #[test]
+    fn chord_fires_once_per_press() {
+        let mut chord = Chord::new(vec![Key::BTN_SELECT, Key::BTN_MODE]);
+        assert!(!chord.update(&key(Key::BTN_SELECT, 1)));
+        assert!(chord.update(&key(Key::BTN_MODE, 1)));
+        // autorepeat and unrelated keys don't fire it again
+        assert!(!chord.update(&key(Key::BTN_MODE, 2)));
+        assert!(!chord.update(&key(Key::BTN_SOUTH, 1)));
+        assert!(chord.is_held());
+
+        assert!(!chord.update(&key(Key::BTN_MODE, 0)));
+        assert!(!chord.is_held());
+        assert!(chord.update(&key(Key::BTN_MODE, 1)));
+    }
+
+    #[test]
+    fn toggle_release_chord() {
+        let mut chord = ReleaseChord::new(vec![Key::BTN_SELECT, Key::BTN_MODE], ReleaseMode::Toggle);
+        assert_eq!(chord.update(&key(Key::BTN_SELECT, 1)), None);
+        assert_eq!(chord.update(&key(Key::BTN_MODE, 1)), Some(true));
+        assert_eq!(chord.update(&key(Key::BTN_MODE, 0)), None);
+        assert_eq!(chord.update(&key(Key::BTN_SELECT, 0)), None);
+
+        assert_eq!(chord.update(&key(Key::BTN_SELECT, 1)), None);
+        assert_eq!(chord.update(&key(Key::BTN_MODE, 1)), Some(false));
+    }
+
+    #[test]
+    fn grabbing_again_swallows_the_chord() {
+        let mut chord = ReleaseChord::new(vec![Key::BTN_SELECT, Key::BTN_MODE], ReleaseMode::Toggle);
+        chord.update(&key(Key::BTN_SELECT, 1));
+        chord.update(&key(Key::BTN_MODE, 1));
+        chord.update(&key(Key::BTN_MODE, 0));
+        chord.update(&key(Key::BTN_SELECT, 0));
+        assert!(!chord.swallows(&key(Key::BTN_SELECT, 1)));
+
+        chord.update(&key(Key::BTN_SELECT, 1));
+        assert_eq!(chord.update(&key(Key::BTN_MODE, 1)), Some(false));
+        assert!(chord.swallows(&key(Key::BTN_MODE, 1)));
+        assert!(!chord.swallows(&key(Key::BTN_SOUTH, 1)));
+        chord.update(&key(Key::BTN_SELECT, 0));
+        assert!(chord.swallows(&key(Key::BTN_SELECT, 0)));
+        // only until it's released
+        chord.update(&key(Key::BTN_SELECT, 1));
+        assert!(!chord.swallows(&key(Key::BTN_SELECT, 1)));
+        chord.update(&key(Key::BTN_MODE, 0));
+        assert!(chord.swallows(&key(Key::BTN_MODE, 0)));
+        assert!(!chord.swallows(&key(Key::BTN_MODE, 1)));
+    }
+
+    #[test]
+    fn hold_release_chord() {
+        let mut chord = ReleaseChord::new(vec![Key::BTN_SELECT, Key::BTN_MODE], ReleaseMode::Hold);
+        chord.update(&key(Key::BTN_SELECT, 1));
+        assert_eq!(chord.update(&key(Key::BTN_MODE, 1)), Some(true));
+        assert_eq!(chord.update(&key(Key::BTN_MODE, 0)), Some(false));
+        // select is still down from the chord
+        assert!(chord.swallows(&key(Key::BTN_SELECT, 0)));
+    }
+
+    #[test]
+    fn empty_chord_never_fires() {
+        let mut chord = Chord::new(Vec::new());
+        assert!(!chord.update(&key(Key::BTN_SOUTH, 1)));
+        assert!(!chord.is_held());
+    }
+
+    fn codes(events: &[InputEvent]) -> Vec<(u16, i32)> {
+        events.iter().map(|ev| (ev.code(), ev.value())).collect()
+    }
+
+    fn aab(swallow: bool) -> Sequence {
+        Sequence::new(vec![Key::BTN_SOUTH, Key::BTN_SOUTH, Key::BTN_EAST], Key::BTN_MODE, Duration::from_millis(500), swallow)
+    }
+
+    // taps `key`, returning everything that went out
+    fn tap(seq: &mut Sequence, key_: Key, now: Instant) -> Vec<(u16, i32)> {
+        let mut out = seq.feed(key(key_, 1), now);
+        out.append(&mut seq.feed(key(key_, 0), now));
+        codes(&out)
+    }
+
+    #[test]
+    fn sequence_taps_its_output() {
+        let mut seq = aab(true);
+        let now = Instant::now();
+        assert!(tap(&mut seq, Key::BTN_SOUTH, now).is_empty());
+        assert!(tap(&mut seq, Key::BTN_SOUTH, now).is_empty());
+        assert_eq!(tap(&mut seq, Key::BTN_EAST, now), [(Key::BTN_MODE.0, 1), (Key::BTN_MODE.0, 0)]);
+        assert_eq!(seq.due_in(now), None);
+    }
+
+    #[test]
+    fn sequence_passes_keys_through_unless_swallowing() {
+        let mut seq = aab(false);
+        let now = Instant::now();
+        assert_eq!(tap(&mut seq, Key::BTN_SOUTH, now), [(Key::BTN_SOUTH.0, 1), (Key::BTN_SOUTH.0, 0)]);
+        assert_eq!(tap(&mut seq, Key::BTN_SOUTH, now), [(Key::BTN_SOUTH.0, 1), (Key::BTN_SOUTH.0, 0)]);
+        assert_eq!(tap(&mut seq, Key::BTN_EAST, now),
+            [(Key::BTN_EAST.0, 1), (Key::BTN_MODE.0, 1), (Key::BTN_MODE.0, 0), (Key::BTN_EAST.0, 0)]);
+    }
+
+    #[test]
+    fn broken_sequence_gives_keys_back() {
+        let mut seq = aab(true);
+        let now = Instant::now();
+        tap(&mut seq, Key::BTN_SOUTH, now);
+        assert_eq!(tap(&mut seq, Key::BTN_NORTH, now),
+            [(Key::BTN_SOUTH.0, 1), (Key::BTN_SOUTH.0, 0), (Key::BTN_NORTH.0, 1), (Key::BTN_NORTH.0, 0)]);
+    }
+
+    #[test]
+    fn sequence_window_runs_out() {
+        let mut seq = aab(true);
+        let now = Instant::now();
+        tap(&mut seq, Key::BTN_SOUTH, now);
+        assert_eq!(seq.due_in(now), Some(Duration::from_millis(500)));
+        assert!(seq.poll(now + Duration::from_millis(400)).is_empty());
+        assert_eq!(codes(&seq.poll(now + Duration::from_millis(600))), [(Key::BTN_SOUTH.0, 1), (Key::BTN_SOUTH.0, 0)]);
+        assert_eq!(seq.due_in(now), None);
+    }
+
+    #[test]
+    fn held_keys_of_a_sequence_stay_swallowed() {
+        let mut seq = aab(true);
+        let now = Instant::now();
+        tap(&mut seq, Key::BTN_SOUTH, now);
+        tap(&mut seq, Key::BTN_SOUTH, now);
+        assert_eq!(codes(&seq.feed(key(Key::BTN_EAST, 1), now)), [(Key::BTN_MODE.0, 1), (Key::BTN_MODE.0, 0)]);
+        assert!(seq.feed(key(Key::BTN_EAST, 0), now).is_empty());
+        assert_eq!(codes(&seq.feed(key(Key::BTN_EAST, 0), now)), [(Key::BTN_EAST.0, 0)]);
+    }
+
+    fn select_start() -> Combo {
+        Combo::new(vec![Key::BTN_SELECT, Key::BTN_START], Key::BTN_MODE, Duration::from_millis(50))
+    }
+
+    #[test]
+    fn combo_acts_as_its_output_while_held() {
+        let mut combo = select_start();
+        let now = Instant::now();
+        assert!(combo.feed(key(Key::BTN_SELECT, 1), now).is_empty());
+        assert_eq!(codes(&combo.feed(key(Key::BTN_START, 1), now)), [(Key::BTN_MODE.0, 1)]);
+        assert_eq!(combo.due_in(now), None);
+        // anything else goes right through
+        assert_eq!(codes(&combo.feed(key(Key::BTN_SOUTH, 1), now)), [(Key::BTN_SOUTH.0, 1)]);
+        // letting go of either ends it, the other one's release is swallowed too
+        assert_eq!(codes(&combo.feed(key(Key::BTN_START, 0), now)), [(Key::BTN_MODE.0, 0)]);
+        assert!(combo.feed(key(Key::BTN_SELECT, 0), now).is_empty());
+        assert_eq!(codes(&combo.feed(key(Key::BTN_SELECT, 1), now)), []);
+    }
+
+    #[test]
+    fn keys_of_a_combo_alone_go_out_late() {
+        let mut combo = select_start();
+        let now = Instant::now();
+        assert!(combo.feed(key(Key::BTN_SELECT, 1), now).is_empty());
+        assert_eq!(combo.due_in(now), Some(Duration::from_millis(50)));
+        assert!(combo.poll(now + Duration::from_millis(40)).is_empty());
+        assert_eq!(codes(&combo.poll(now + Duration::from_millis(60))), [(Key::BTN_SELECT.0, 1)]);
+        assert_eq!(codes(&combo.feed(key(Key::BTN_SELECT, 0), now + Duration::from_millis(70))), [(Key::BTN_SELECT.0, 0)]);
+    }
+
+    #[test]
+    fn tapped_keys_of_a_combo_keep_their_order() {
+        let mut combo = select_start();
+        let now = Instant::now();
+        combo.feed(key(Key::BTN_START, 1), now);
+        assert_eq!(codes(&combo.feed(key(Key::BTN_START, 0), now)), [(Key::BTN_START.0, 1), (Key::BTN_START.0, 0)]);
+        // and too late for the combo is too late
+        combo.feed(key(Key::BTN_SELECT, 1), now);
+        let late = now + Duration::from_millis(60);
+        assert_eq!(codes(&combo.feed(key(Key::BTN_START, 1), late)), [(Key::BTN_SELECT.0, 1)]);
+        assert_eq!(codes(&combo.poll(late + Duration::from_millis(60))), [(Key::BTN_START.0, 1)]);
+    }
+}
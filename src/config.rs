@@ -0,0 +1,576 @@
+use anyhow::{bail, Context, Result};
+use evdev::InputId;
+use libdogd::log_info;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+static DEFAULT_CONFIG_PATH: &str = "/etc/rinputer4_5.toml";
+static DEFAULT_PROFILE_DIR: &str = "/etc/rinputer4_5.d";
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// normalize everything into an Xbox 360 pad
+    #[default]
+    Xbox,
+    /// mirror the capabilities of the devices present at startup and relay events untouched
+    Passthrough,
+}
+
+// how dpads of several devices combine when they disagree
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HatPolicy {
+    /// the most recently pressed direction wins
+    #[default]
+    Last,
+    /// the device listed first in hat_priority wins
+    Priority,
+    /// opposing directions cancel out
+    Neutral,
+}
+
+// what happens to a device pinned to a player someone else already is
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotConflict {
+    /// both play on that pad
+    #[default]
+    Merge,
+    /// the device that came second is left alone
+    Reject,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProfileCycle {
+    /// keys that all have to be held down to switch to the next profile, e.g. ["select", "guide"]
+    pub chord: Vec<String>,
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseMode {
+    /// pressing the chord releases the device, pressing it again grabs it back
+    #[default]
+    Toggle,
+    /// the device is released only while the chord is held
+    Hold,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReleaseChordConfig {
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub mode: ReleaseMode,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SequenceConfig {
+    /// keys pressed one after another, e.g. ["a", "a", "b"]
+    pub keys: Vec<String>,
+    /// key tapped once the whole sequence went in within the window
+    pub output: String,
+    pub window_ms: u64,
+    /// keep the keys of the sequence from going out while it's being typed
+    #[serde(default)]
+    pub swallow: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ComboConfig {
+    /// keys held down together, e.g. ["select", "start"]
+    pub keys: Vec<String>,
+    /// key held for as long as the combo is
+    pub output: String,
+    /// how far apart the presses may be, and how late each key goes out on its own
+    pub window_ms: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModifierLayer {
+    /// vendor:product of the device holding the modifier, e.g. a foot pedal
+    pub device: String,
+    pub key: String,
+    /// key remaps applied to every device while the modifier is held
+    pub remap: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SmoothingConfig {
+    pub axis: String,
+    /// changes larger than this between two events get smoothed
+    pub threshold: i32,
+    /// how much of what's left of such a change goes out every 10ms, 0.0 to 1.0
+    pub factor: f32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TwoStageConfig {
+    /// fractions of a full pull, 0.0 to 1.0
+    pub soft: f32,
+    pub full: f32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MixInput {
+    pub axis: String,
+    pub weight: f32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MixConfig {
+    /// the axis to replace with the weighted sum of `inputs`
+    pub output: String,
+    pub inputs: Vec<MixInput>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct DeviceConfig {
+    /// vendor:product in hex, e.g. "1209:3100"
+    pub id: String,
+    /// matches a single node instead, to tell identical controllers apart
+    pub path: Option<PathBuf>,
+    /// shown in logs and hooks instead of the name the device reports
+    pub name: Option<String>,
+    /// mounted upside down, inverts sticks and dpad and swaps shoulders, triggers and thumb buttons
+    pub flip: bool,
+    /// triggers rest at their maximum and decrease when pulled, autodetected when unset from all
+    /// analog triggers of the device resting at their maximum
+    pub invert_triggers: Option<bool>,
+    /// whether negative trigger values, e.g. -32768..32767, are part of the pull or should be ignored
+    pub bidirectional_triggers: Option<bool>,
+    /// axes that point the wrong way, e.g. ["ABS_RY"], inverted after scaling
+    pub invert: Vec<String>,
+    /// left and right stick wired up the other way round
+    pub swap_sticks: bool,
+    /// always the same player, counting from 1, when there's more than one pad
+    pub player: Option<usize>,
+}
+
+/// the complete remap of one device, each file in the profile directory holds one
+#[derive(Deserialize, Debug)]
+pub struct DeviceProfile {
+    /// vendor:product in hex, e.g. "1209:3100"
+    #[serde(default)]
+    pub id: String,
+    /// or a single node, /dev/input/by-id/ symlinks are fine too
+    pub path: Option<PathBuf>,
+    /// e.g. BTN_EAST = "BTN_SOUTH", or BTN_TL2 = "ABS_Z 255 0" for axis values on press and release
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    /// e.g. ABS_RX = "ABS_X"
+    #[serde(default)]
+    pub axes: HashMap<String, String>,
+    #[serde(skip)]
+    pub source: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub profile: Profile,
+    /// switch between profiles at runtime using a button chord
+    pub profile_cycle: Option<ProfileCycle>,
+    /// hand grabbed devices back to the system using a button chord
+    pub release_chord: Option<ReleaseChordConfig>,
+    /// device nodes that are never grabbed, /dev/input/by-id/ symlinks are fine too
+    pub exclude: Vec<PathBuf>,
+    /// print a status line and ring the terminal bell whenever a device is grabbed or released
+    pub notify: bool,
+    /// programs to run with the device name as their only argument on grab and release
+    pub on_grab: Option<String>,
+    pub on_release: Option<String>,
+    /// apply matching mappings from the SDL_GAMECONTROLLERCONFIG environment variable
+    pub sdl_mappings: bool,
+    /// how long a disconnected device may take to come back before everything it held is released
+    pub disconnect_grace_ms: u64,
+    /// log the event rate of every grabbed device this often, 0 disables it
+    pub rate_log_interval_secs: u64,
+    /// log one in this many events rinputer doesn't handle, 0 disables it
+    pub log_unknown_every: u64,
+    /// keep retrying grabs for this long after startup, while logind & co. may still hold devices
+    pub boot_grab_window_secs: u64,
+    /// debugging aid, forward all events verbatim without any scaling or remapping
+    pub flat: bool,
+    /// report both triggers on ABS_Z, left negative and right positive, for old single axis games
+    pub combined_triggers: bool,
+    /// use -32767..32767 for sticks so full deflection is equally far in both directions
+    pub symmetric_analog: bool,
+    /// full pull of the triggers, some games want more resolution than 0..255, e.g. 1023
+    pub trigger_max: i32,
+    /// axes made up of other axes, e.g. averaging both sticks for one handed play
+    pub mix: Vec<MixConfig>,
+    /// radial deadzone of both sticks as a fraction of full deflection, 0 disables it
+    pub deadzone: f32,
+    /// soften fast flicks on these axes without adding latency to slow movement
+    pub smoothing: Vec<SmoothingConfig>,
+    /// send axis updates at most once per this many milliseconds, only the latest value of each, 0 disables it
+    pub coalesce_ms: u64,
+    /// axes that skip smoothing, coalescing and other filtering no matter what, e.g. a precise analog trigger
+    pub raw_axes: Vec<String>,
+    /// buttons that latch on a tap and release on the next one, for accessibility
+    pub sticky: Vec<String>,
+    /// button sequences across all devices that tap another button
+    #[serde(rename = "sequence")]
+    pub sequences: Vec<SequenceConfig>,
+    /// keys held together acting as another, e.g. select and start as BTN_MODE on pads without one
+    #[serde(rename = "combo")]
+    pub combos: Vec<ComboConfig>,
+    /// keys of keyboard nodes belonging to a gamepad, mapped to pad buttons, e.g. { KEY_VOLUMEUP = "BTN_TRIGGER_HAPPY5" }
+    pub companion_keys: HashMap<String, String>,
+    /// keep the mapped keys from the rest of the system too, which takes the whole keyboard node
+    pub grab_companions: bool,
+    /// holding this key, e.g. "select", turns the dpad into the right stick
+    pub dpad_right_stick: Option<String>,
+    /// remap keys of every device while a key on one of them is held
+    pub modifier_layer: Option<ModifierLayer>,
+    /// after this many seconds of sticks idling near center, send them exactly centered, 0 disables it
+    pub idle_center_secs: u64,
+    /// check this often that the virtual device still exists and recreate it if not, 0 disables it
+    pub health_check_secs: u64,
+    /// report soft and full trigger pulls as BTN_TRIGGER_HAPPY1/2 (left) and 3/4 (right) too
+    pub two_stage_triggers: Option<TwoStageConfig>,
+    /// pass rumble from games on to whichever pads can actually vibrate
+    pub rumble: bool,
+    /// give each controller a virtual pad of its own, up to this many players, 1 merges them all into one
+    pub pads: usize,
+    /// what to do when the dpads of several devices point different ways
+    pub hat_conflict: HatPolicy,
+    /// vendor:product ids in order of precedence for the priority hat_conflict policy
+    pub hat_priority: Vec<String>,
+    /// what to do when two devices are pinned to the same player
+    pub slot_conflict: SlotConflict,
+    /// per device overrides
+    #[serde(rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+    /// directory of per device remap profiles, replacing the builtin quirks for their devices
+    pub profile_dir: PathBuf,
+    #[serde(skip)]
+    pub device_profiles: Vec<DeviceProfile>,
+    /// vendor:product ids from --allow, always grabbed whatever they look like
+    #[serde(skip)]
+    pub allow: Vec<(u16, u16)>,
+    /// vendor:product ids from --deny, never grabbed
+    #[serde(skip)]
+    pub deny: Vec<(u16, u16)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            profile: Profile::default(),
+            profile_cycle: None,
+            release_chord: None,
+            exclude: Vec::new(),
+            notify: false,
+            on_grab: None,
+            on_release: None,
+            sdl_mappings: false,
+            disconnect_grace_ms: 0,
+            rate_log_interval_secs: 0,
+            log_unknown_every: 0,
+            boot_grab_window_secs: 10,
+            flat: false,
+            combined_triggers: false,
+            symmetric_analog: false,
+            trigger_max: 255,
+            mix: Vec::new(),
+            deadzone: 0.1,
+            smoothing: Vec::new(),
+            raw_axes: Vec::new(),
+            coalesce_ms: 0,
+            sticky: Vec::new(),
+            sequences: Vec::new(),
+            combos: Vec::new(),
+            companion_keys: HashMap::new(),
+            grab_companions: false,
+            dpad_right_stick: None,
+            modifier_layer: None,
+            idle_center_secs: 0,
+            health_check_secs: 0,
+            two_stage_triggers: None,
+            rumble: true,
+            pads: 1,
+            hat_conflict: HatPolicy::default(),
+            hat_priority: Vec::new(),
+            slot_conflict: SlotConflict::default(),
+            devices: Vec::new(),
+            profile_dir: PathBuf::from(DEFAULT_PROFILE_DIR),
+            device_profiles: Vec::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+pub fn parse_vid_pid(spec: &str) -> Option<(u16, u16)> {
+    let (vid, pid) = spec.split_once(':')?;
+    Some((u16::from_str_radix(vid, 16).ok()?, u16::from_str_radix(pid, 16).ok()?))
+}
+
+impl Config {
+    // a missing config file is fine, a broken one is not
+    pub fn load() -> Result<Config> {
+        let path = env::var("RINPUTER_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        let mut config: Config = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse config file {}", path))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read config file {}", path)),
+        };
+        config.device_profiles = load_profiles(&config.profile_dir)?;
+        Ok(config)
+    }
+
+    // a profile for the node itself wins over one for its vendor:product
+    pub fn device_profile(&self, id: InputId, node: &Path) -> Option<&DeviceProfile> {
+        self.device_profiles.iter()
+            .find(|profile| profile.path.as_deref().is_some_and(|path| same_node(path, node)))
+            .or_else(|| self.device_profiles.iter()
+                .find(|profile| parse_vid_pid(&profile.id) == Some((id.vendor(), id.product()))))
+    }
+
+    // an entry for the node itself wins over one for its vendor:product, like names do
+    pub fn device(&self, id: InputId, node: &Path) -> Option<&DeviceConfig> {
+        self.devices.iter()
+            .find(|dev| dev.path.as_deref().is_some_and(|path| same_node(path, node)))
+            .or_else(|| self.devices.iter()
+                .find(|dev| parse_vid_pid(&dev.id) == Some((id.vendor(), id.product()))))
+    }
+
+    // a name set for the node itself wins over one set for its vendor:product
+    pub fn device_name(&self, id: InputId, node: &Path) -> Option<&str> {
+        let by_path = self.devices.iter()
+            .filter(|dev| dev.path.as_deref().is_some_and(|path| same_node(path, node)))
+            .find_map(|dev| dev.name.as_deref());
+        by_path.or_else(|| self.devices.iter()
+            .filter(|dev| parse_vid_pid(&dev.id) == Some((id.vendor(), id.product())))
+            .find_map(|dev| dev.name.as_deref()))
+    }
+
+    pub fn analog_range(&self) -> (i32, i32) {
+        if self.symmetric_analog {
+            (-crate::MAX_OUT_ANALOG, crate::MAX_OUT_ANALOG)
+        } else {
+            (crate::MIN_OUT_ANALOG, crate::MAX_OUT_ANALOG)
+        }
+    }
+
+    pub fn is_excluded(&self, node: &Path) -> bool {
+        self.exclude.iter()
+            .any(|excluded| same_node(excluded, node))
+    }
+
+    // --allow vvvv:pppp and --deny vvvv:pppp, each as often as needed
+    pub fn apply_args(&mut self, mut args: impl Iterator<Item = String>) -> Result<()> {
+        while let Some(arg) = args.next() {
+            let list = match arg.as_str() {
+                "--allow" => &mut self.allow,
+                "--deny" => &mut self.deny,
+                _ => bail!("Unknown argument {}", arg),
+            };
+            let spec = args.next().with_context(|| format!("{} needs a vendor:product id, e.g. 045e:028e", arg))?;
+            let id = parse_vid_pid(&spec).with_context(|| format!("Invalid vendor:product id {} for {}", spec, arg))?;
+            list.push(id);
+        }
+        Ok(())
+    }
+
+    // None leaves it to the heuristics, deny wins if an id is in both
+    pub fn device_override(&self, id: InputId) -> Option<bool> {
+        let id = (id.vendor(), id.product());
+        if self.deny.contains(&id) {
+            Some(false)
+        } else if self.allow.contains(&id) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+// same rules as the config file itself, only *.toml files are considered
+pub fn load_profiles(dir: &Path) -> Result<Vec<DeviceProfile>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read profile directory {}", dir.display())),
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+
+    // one broken profile only costs its own device the remap, not everyone their input
+    Ok(paths.into_iter()
+        .filter_map(|path| {
+            let parsed = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read profile {}", path.display()))
+                .and_then(|contents| toml::from_str::<DeviceProfile>(&contents)
+                    .with_context(|| format!("Failed to parse profile {}", path.display())));
+            match parsed {
+                Ok(mut profile) => {
+                    profile.source = path;
+                    Some(profile)
+                },
+                Err(e) => {
+                    log_info(format!("Skipping profile: {:#}", e));
+                    None
+                },
+            }
+        })
+        .collect())
+}
+
+// configured paths may be /dev/input/by-id/ symlinks
+fn same_node(configured: &Path, node: &Path) -> bool {
+    configured == node || fs::canonicalize(configured).is_ok_and(|real| real == node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn analog_range() {
+        assert_eq!(Config::default().analog_range(), (-32768, 32767));
+        let config = Config { symmetric_analog: true, ..Config::default() };
+        assert_eq!(config.analog_range(), (-32767, 32767));
+    }
+
+    #[test]
+    fn excluded_by_path() {
+        let config = Config {
+            exclude: vec![PathBuf::from("/dev/input/event3")],
+            ..Config::default()
+        };
+        assert!(config.is_excluded(Path::new("/dev/input/event3")));
+        assert!(!config.is_excluded(Path::new("/dev/input/event4")));
+    }
+
+    #[test]
+    fn excluded_by_symlink() {
+        let dir = env::temp_dir().join(format!("rinputer-exclude-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let node = dir.join("event3");
+        let link = dir.join("usb-pad-event-joystick");
+        fs::write(&node, "").unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&node, &link).unwrap();
+
+        let config = Config {
+            exclude: vec![link],
+            ..Config::default()
+        };
+        let excluded = config.is_excluded(&fs::canonicalize(&node).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(excluded);
+    }
+
+    #[test]
+    fn names_by_node_win() {
+        let config = Config {
+            devices: vec![
+                DeviceConfig { id: "1209:3100".into(), name: Some("Handheld".into()), ..Default::default() },
+                DeviceConfig { path: Some("/dev/input/event5".into()), name: Some("Player two".into()), ..Default::default() },
+            ],
+            ..Config::default()
+        };
+        let id = InputId::new(evdev::BusType::BUS_USB, 0x1209, 0x3100, 1);
+        assert_eq!(config.device_name(id, Path::new("/dev/input/event4")), Some("Handheld"));
+        assert_eq!(config.device_name(id, Path::new("/dev/input/event5")), Some("Player two"));
+        let other = InputId::new(evdev::BusType::BUS_USB, 0x045e, 0x028e, 1);
+        assert_eq!(config.device_name(other, Path::new("/dev/input/event6")), None);
+    }
+
+    #[test]
+    fn devices_by_node_win() {
+        let config = Config {
+            devices: vec![
+                DeviceConfig { id: "1209:3100".into(), flip: true, ..Default::default() },
+                DeviceConfig { path: Some("/dev/input/event5".into()), swap_sticks: true, ..Default::default() },
+            ],
+            ..Config::default()
+        };
+        let id = InputId::new(evdev::BusType::BUS_USB, 0x1209, 0x3100, 1);
+        assert!(config.device(id, Path::new("/dev/input/event4")).is_some_and(|dev| dev.flip));
+        assert!(config.device(id, Path::new("/dev/input/event5")).is_some_and(|dev| dev.swap_sticks && !dev.flip));
+        // a path only entry needs no vendor:product at all
+        let other = InputId::new(evdev::BusType::BUS_USB, 0x045e, 0x028e, 1);
+        assert!(config.device(other, Path::new("/dev/input/event5")).is_some_and(|dev| dev.swap_sticks));
+        assert!(config.device(other, Path::new("/dev/input/event6")).is_none());
+    }
+
+    #[test]
+    fn vid_pid_is_hex() {
+        assert_eq!(parse_vid_pid("045e:028e"), Some((0x045e, 0x028e)));
+        assert_eq!(parse_vid_pid("1209:3100"), Some((0x1209, 0x3100)));
+        assert_eq!(parse_vid_pid("045e"), None);
+        assert_eq!(parse_vid_pid("045e:xbox"), None);
+        assert_eq!(parse_vid_pid("10000:0001"), None);
+    }
+
+    #[test]
+    fn allow_and_deny_from_args() {
+        let mut config = Config::default();
+        let args = ["--allow", "045e:028e", "--deny", "1209:3100", "--deny", "045e:028e"];
+        config.apply_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(config.allow, [(0x045e, 0x028e)]);
+        assert_eq!(config.deny, [(0x1209, 0x3100), (0x045e, 0x028e)]);
+    }
+
+    #[test]
+    fn broken_args_are_errors() {
+        for args in [&["--grab"][..], &["--allow"], &["--deny", "045e"]] {
+            let mut config = Config::default();
+            assert!(config.apply_args(args.iter().map(|arg| arg.to_string())).is_err());
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let config = Config {
+            allow: vec![(0x045e, 0x028e), (0x1209, 0x3100)],
+            deny: vec![(0x045e, 0x028e)],
+            ..Config::default()
+        };
+        let id = |vendor, product| InputId::new(evdev::BusType::BUS_USB, vendor, product, 1);
+        assert_eq!(config.device_override(id(0x045e, 0x028e)), Some(false));
+        assert_eq!(config.device_override(id(0x1209, 0x3100)), Some(true));
+        assert_eq!(config.device_override(id(0x054c, 0x09cc)), None);
+    }
+
+    #[test]
+    fn toml_broken_profiles_are_skipped() {
+        let dir = env::temp_dir().join(format!("rinputer-profiles-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("good.toml"), "id = \"1209:3100\"\n[keys]\nBTN_EAST = \"BTN_SOUTH\"\n").unwrap();
+        fs::write(dir.join("broken.toml"), "id = \"045e:028e\"\n[keys\n").unwrap();
+        let config_file = dir.join("config");
+        fs::write(&config_file, format!("profile_dir = \"{}\"\n", dir.display())).unwrap();
+
+        let profiles = load_profiles(&dir).unwrap();
+        // and starting up doesn't mind either
+        env::set_var("RINPUTER_CONFIG", &config_file);
+        let loaded = Config::load();
+        env::remove_var("RINPUTER_CONFIG");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].id, "1209:3100");
+        let config = loaded.unwrap();
+        assert_eq!(config.device_profiles.len(), 1);
+        assert_eq!(config.device_profiles[0].source, dir.join("good.toml"));
+    }
+}
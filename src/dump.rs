@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use evdev::{
+    AbsInfo,
+    AbsoluteAxisType,
+    Device,
+    InputId,
+    Key,
+};
+use std::{
+    fmt::Write,
+    fs,
+    path::Path,
+};
+use crate::remap::{self, Code};
+
+// everything a dump tells about a device
+struct Capabilities<'a> {
+    name: &'a str,
+    id: InputId,
+    phys: &'a str,
+    uniq: &'a str,
+    props: Vec<u16>,
+    keys: Vec<Key>,
+    axes: Vec<(AbsoluteAxisType, Option<AbsInfo>)>,
+}
+
+// a profile for the device mapping everything to itself, ready to be edited into a quirk
+// and dropped into the profile directory. whatever the profile can't express goes in comments
+pub fn describe(dev: &Device, node: &Path) -> String {
+    let absinfo = dev.get_abs_state().ok();
+    let caps = Capabilities {
+        name: dev.name().unwrap_or("<invalid name>"),
+        id: dev.input_id(),
+        phys: dev.physical_path().unwrap_or(""),
+        uniq: dev.unique_name().unwrap_or(""),
+        props: dev.properties().iter().map(|prop| prop.0).collect(),
+        keys: dev.supported_keys().into_iter().flat_map(|keys| keys.iter()).collect(),
+        axes: dev.supported_absolute_axes().into_iter().flat_map(|axes| axes.iter())
+            .map(|axis| (axis, absinfo.as_ref().map(|absinfo| {
+                let info = absinfo[axis.0 as usize];
+                AbsInfo::new(info.value, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution)
+            })))
+            .collect(),
+    };
+    render(&caps, node)
+}
+
+fn render(caps: &Capabilities, node: &Path) -> String {
+    let id = caps.id;
+    let mut out = String::new();
+    let _ = writeln!(out, "# {}", caps.name);
+    let _ = writeln!(out, "# bus {:#06x} vendor {:#06x} product {:#06x} version {:#06x}",
+        id.bus_type().0, id.vendor(), id.product(), id.version());
+    let _ = writeln!(out, "# phys {} uniq {}", caps.phys, caps.uniq);
+    let _ = writeln!(out, "# properties {:?}", caps.props);
+    let _ = writeln!(out, "id = \"{:04x}:{:04x}\"", id.vendor(), id.product());
+    let _ = writeln!(out, "# path = \"{}\"", node.display());
+
+    let _ = writeln!(out, "\n[keys]");
+    for &key in &caps.keys {
+        // names where we understand them, raw codes otherwise
+        let name = format!("{:?}", key);
+        let name = match remap::parse_key(&name) {
+            Some(parsed) if parsed == key => name,
+            _ => format!("{:#x}", key.0),
+        };
+        let _ = writeln!(out, "{} = \"{}\"", name, name);
+    }
+
+    let _ = writeln!(out, "\n[axes]");
+    for &(axis, info) in &caps.axes {
+        if let Some(info) = info {
+            let _ = writeln!(out, "# min {} max {} fuzz {} flat {} resolution {}",
+                info.minimum(), info.maximum(), info.fuzz(), info.flat(), info.resolution());
+        }
+        let name = format!("{:?}", axis);
+        match remap::parse_code(&name) {
+            Some(Code::Abs(parsed)) if parsed == axis => { let _ = writeln!(out, "{} = \"{}\"", name, name); },
+            _ => { let _ = writeln!(out, "# axis {:#x} can't be remapped", axis.0); },
+        }
+    }
+    out
+}
+
+// rinputer4_5 --dump /dev/input/eventN [file]
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let node = args.next().context("Usage: rinputer4_5 --dump /dev/input/eventN [file]")?;
+    let dev = Device::open(&node).with_context(|| format!("Failed to open {}", node))?;
+    let description = describe(&dev, Path::new(&node));
+
+    match args.next() {
+        Some(file) => fs::write(&file, description).with_context(|| format!("Failed to write {}", file)),
+        None => {
+            print!("{}", description);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{BusType, EventType, InputEvent};
+    use crate::{config::{self, DeviceProfile}, remap::RemapTable};
+
+    fn pad() -> Capabilities<'static> {
+        Capabilities {
+            name: "Generic USB pad",
+            id: InputId::new(BusType::BUS_USB, 0x1209, 0x3100, 0x0111),
+            phys: "usb-0000:00:14.0-2/input0",
+            uniq: "",
+            props: Vec::new(),
+            // the odd one out has no name we know
+            keys: vec![Key::BTN_SOUTH, Key::BTN_EAST, Key::BTN_SELECT, Key(0x2fe)],
+            axes: vec![
+                (AbsoluteAxisType::ABS_X, Some(AbsInfo::new(128, 0, 255, 0, 15, 0))),
+                (AbsoluteAxisType::ABS_HAT0X, None),
+                (AbsoluteAxisType::ABS_MT_POSITION_X, Some(AbsInfo::new(0, 0, 1920, 0, 0, 12))),
+            ],
+        }
+    }
+
+    #[test]
+    fn ranges_and_leftovers_are_comments() {
+        let dump = render(&pad(), Path::new("/dev/input/event3"));
+        assert!(dump.contains("id = \"1209:3100\"\n"));
+        assert!(dump.contains("# path = \"/dev/input/event3\"\n"));
+        assert!(dump.contains("# min 0 max 255 fuzz 0 flat 15 resolution 0\n"));
+        assert!(dump.contains(&format!("# axis {:#x} can't be remapped\n", AbsoluteAxisType::ABS_MT_POSITION_X.0)));
+    }
+
+    #[test]
+    fn toml_dump_round_trips() {
+        let dump = render(&pad(), Path::new("/dev/input/event3"));
+        let profile = toml::from_str::<DeviceProfile>(&dump).unwrap();
+        assert_eq!(profile.id, "1209:3100");
+        assert_eq!(profile.path, None);
+        assert_eq!(profile.keys.len(), 4);
+        assert_eq!(profile.axes.len(), 2);
+
+        // as is, it changes nothing at all
+        let table = RemapTable::from_profile(&profile);
+        for key in pad().keys {
+            let mut ev = InputEvent::new(EventType::KEY, key.0, 1);
+            table.apply(&mut ev);
+            assert_eq!((ev.event_type(), ev.code()), (EventType::KEY, key.0));
+        }
+        for (axis, _) in pad().axes {
+            let mut ev = InputEvent::new(EventType::ABSOLUTE, axis.0, 1);
+            table.apply(&mut ev);
+            assert_eq!((ev.event_type(), ev.code()), (EventType::ABSOLUTE, axis.0));
+        }
+    }
+
+    #[test]
+    fn toml_dump_loads_from_the_profile_directory() {
+        let dir = std::env::temp_dir().join(format!("rinputer-dump-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("pad.toml");
+        fs::write(&file, render(&pad(), Path::new("/dev/input/event3"))).unwrap();
+
+        let profiles = config::load_profiles(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].id, "1209:3100");
+        assert_eq!(profiles[0].source, file);
+        assert_eq!(profiles[0].keys.len(), 4);
+    }
+}
@@ -1,22 +1,47 @@
+mod chord;
+mod config;
+mod dump;
+mod output;
+mod pad;
+mod passthrough;
+mod pipeline;
+mod remap;
+mod rumble;
+mod sdl;
+mod shutdown;
+mod state;
+mod transform;
+
 use anyhow::{Context, Result};
 use evdev::{
     UinputAbsSetup,
     uinput::VirtualDeviceBuilder,
     AbsInfo,
     InputId,
+    BusType,
     Key,
     AbsoluteAxisType,
     InputEvent,
     Device,
     InputEventKind,
     EventType,
+    PropType,
+    Synchronization,
+    uinput::VirtualDevice,
 };
 use std::{
     thread,
-    sync::mpsc,
-    time::Duration,
+    sync::{mpsc, Arc, Mutex, atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering}},
+    time::{Duration, Instant},
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    fs,
 };
 use libdogd::{log_debug, log_info};
+use config::{Config, Profile};
+use chord::{Chord, Combo, ReleaseChord, Sequence};
+use state::{Frame, FrameSender, HeldState, PendingFrame, RateCounter};
 
 static MAX_OUT_ANALOG: i32 = 32767;
 static MIN_OUT_ANALOG: i32 = -32768;
@@ -25,14 +50,74 @@ static MIN_OUT_HAT: i32 = -1;
 static MAX_OUT_HAT: i32 = 1;
 
 static MIN_OUT_TRIG: i32 = 0;
-static MAX_OUT_TRIG: i32 = 255;
+// 255 unless configured otherwise, set once at startup
+static MAX_OUT_TRIG: AtomicI32 = AtomicI32::new(255);
+
+fn max_out_trig() -> i32 {
+    MAX_OUT_TRIG.load(Ordering::Relaxed)
+}
+
+// how long a frame waits for its SYN_REPORT before it goes out anyway
+static FRAME_DEADLINE: Duration = Duration::from_millis(20);
+
+// marks devices created by rinputer
+static RINPUTER_VERSION: u16 = 0x2137;
+
+// state shared between the watcher and all handler threads
+struct Shared {
+    started: Instant,
+    // device nodes of every virtual device we created, so we never grab our own output
+    own_nodes: Mutex<HashSet<PathBuf>>,
+    // held state and frame source of devices that went away, until they come back or their grace period runs out
+    disconnected: Mutex<HashMap<String, (HeldState, u64)>>,
+    // frame source of the next device
+    next_source: AtomicU64,
+    // position in the configured profile cycle
+    profile: AtomicUsize,
+    // whether the modifier of the modifier layer is held, on whichever device it lives
+    modifier: AtomicBool,
+    // nodes that have a handler, or were found not worth one, so the watcher leaves them be
+    handled: Mutex<HashSet<PathBuf>>,
+    // second handles on the pads that can rumble
+    rumblers: Arc<rumble::Rumblers>,
+    // devices currently grabbed, main waits for these to be let go before exiting
+    grabbed: AtomicUsize,
+    // which device is which player, when each gets a virtual pad of its own
+    slots: Mutex<state::Slots>,
+}
+
+impl Shared {
+    fn new(config: &Config) -> Shared {
+        Shared {
+            started: Instant::now(),
+            own_nodes: Mutex::new(HashSet::new()),
+            disconnected: Mutex::new(HashMap::new()),
+            next_source: AtomicU64::new(0),
+            profile: AtomicUsize::new(0),
+            modifier: AtomicBool::new(false),
+            handled: Mutex::new(HashSet::new()),
+            rumblers: Arc::new(rumble::Rumblers::default()),
+            grabbed: AtomicUsize::new(0),
+            slots: Mutex::new(state::Slots::new(config.pads, config.slot_conflict)),
+        }
+    }
+}
+
+fn active_profile(config: &Config, shared: &Shared) -> Profile {
+    match &config.profile_cycle {
+        Some(cycle) if !cycle.profiles.is_empty() => {
+            cycle.profiles[shared.profile.load(Ordering::Relaxed) % cycle.profiles.len()]
+        },
+        _ => config.profile,
+    }
+}
 
 #[inline]
 fn has_key(dev: &Device, key: evdev::Key) -> bool {
-    dev.supported_keys().map_or(false, |keys| keys.contains(key))
+    dev.supported_keys().is_some_and(|keys| keys.contains(key))
 }
 
-fn generic_dac(ev: &mut InputEvent, _: mpsc::Sender<InputEvent>) {
+fn generic_dac(ev: &mut InputEvent, _: &mut Vec<InputEvent>) {
     let InputEventKind::Key(key) = ev.kind() else { return };
     let type_value = match key {
         Key::BTN_DPAD_UP    => (AbsoluteAxisType::ABS_HAT0Y.0, if ev.value() == 0 { 0 } else { -1 }),
@@ -40,14 +125,14 @@ fn generic_dac(ev: &mut InputEvent, _: mpsc::Sender<InputEvent>) {
         Key::BTN_DPAD_LEFT  => (AbsoluteAxisType::ABS_HAT0X.0, if ev.value() == 0 { 0 } else { -1 }),
         Key::BTN_DPAD_RIGHT => (AbsoluteAxisType::ABS_HAT0X.0, if ev.value() == 0 { 0 } else {  1 }),
         
-        Key::BTN_TL2 => (AbsoluteAxisType::ABS_Z.0, if ev.value() == 0 { MIN_OUT_TRIG } else { MAX_OUT_TRIG }),
-        Key::BTN_TR2 => (AbsoluteAxisType::ABS_RZ.0, if ev.value() == 0 { MIN_OUT_TRIG } else { MAX_OUT_TRIG }),
+        Key::BTN_TL2 => (AbsoluteAxisType::ABS_Z.0, if ev.value() == 0 { MIN_OUT_TRIG } else { max_out_trig() }),
+        Key::BTN_TR2 => (AbsoluteAxisType::ABS_RZ.0, if ev.value() == 0 { MIN_OUT_TRIG } else { max_out_trig() }),
         _ => return,
     };
     *ev = InputEvent::new(EventType::ABSOLUTE, type_value.0, type_value.1);
 }
 
-fn rg351m(ev: &mut InputEvent, _: mpsc::Sender<InputEvent>) {
+fn rg351m(ev: &mut InputEvent, _: &mut Vec<InputEvent>) {
     let InputEventKind::Key(key) = ev.kind() else { return };
     // yes this is for real. maybe the engineers were drunk, *shrugs*
     let new_ev = match key {
@@ -63,8 +148,8 @@ fn rg351m(ev: &mut InputEvent, _: mpsc::Sender<InputEvent>) {
         Key::BTN_WEST       => InputEvent::new(EventType::KEY, Key::BTN_TL.0, ev.value()),
         Key::BTN_Z          => InputEvent::new(EventType::KEY, Key::BTN_TR.0, ev.value()),
         // triggers
-        Key::BTN_SELECT     => InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, ev.value() * MAX_OUT_TRIG),
-        Key::BTN_START      => InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RZ.0, ev.value() * MAX_OUT_TRIG),
+        Key::BTN_SELECT     => InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, ev.value() * max_out_trig()),
+        Key::BTN_START      => InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RZ.0, ev.value() * max_out_trig()),
         // select start
         Key::BTN_TR         => InputEvent::new(EventType::KEY, Key::BTN_SELECT.0, ev.value()),
         Key::BTN_TL         => InputEvent::new(EventType::KEY, Key::BTN_START.0, ev.value()),
@@ -73,135 +158,772 @@ fn rg351m(ev: &mut InputEvent, _: mpsc::Sender<InputEvent>) {
     *ev = new_ev;
 }
 
-// TODO: multiple remap quirks
-fn get_remap_fn(dev: &mut Device) -> Option<fn(&mut InputEvent, mpsc::Sender<InputEvent>)> {
+// Anbernic RG353 series, Powkiddy RGB30/RK2023 and friends, all share the "retrogame_joypad" driver
+fn retrogame_joypad(ev: &mut InputEvent, extra: &mut Vec<InputEvent>) {
+    let InputEventKind::Key(key) = ev.kind() else { return };
+    match key {
+        // A and B are labelled nintendo-style but report positionally swapped codes
+        Key::BTN_EAST       => *ev = InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, ev.value()),
+        Key::BTN_SOUTH      => *ev = InputEvent::new(EventType::KEY, Key::BTN_EAST.0, ev.value()),
+        // the function key already reports as BTN_MODE so it doubles as guide, volume keys never
+        // show up here, they live on a separate gpio-keys node that companion_keys can map
+        Key::BTN_MODE       => (),
+        // dpad and digital triggers are regular DAC-style
+        _ => generic_dac(ev, extra),
+    }
+}
+
+// rewrites the event in place, anything it needs to send on top goes in the same frame
+type RemapFn = Box<dyn Fn(&mut InputEvent, &mut Vec<InputEvent>)>;
+
+// profiles from the profile directory win over the builtin quirks, so a device can be fixed without a rebuild
+fn get_remap_fn(dev: &mut Device, path: &Path, config: &Config) -> Option<RemapFn> {
+    if config.sdl_mappings {
+        if let Some(table) = sdl::remap_for(dev) {
+            log_info("Applying mapping from SDL_GAMECONTROLLERCONFIG");
+            return Some(Box::new(move |ev, _| table.apply(ev)));
+        }
+    }
+
+    if let Some(profile) = config.device_profile(dev.input_id(), path) {
+        log_info(format!("Applying remap profile {}", profile.source.display()));
+        let table = remap::RemapTable::from_profile(profile);
+        return Some(Box::new(move |ev, _| table.apply(ev)));
+    }
+
     let inputid = dev.input_id();
     if inputid.vendor() == 0x1209 && inputid.product() == 0x3100 {
         log_info("Applying rg351m quirk");
-        return Some(rg351m);
+        return Some(Box::new(rg351m));
+    }
+    if inputid.vendor() == 0x484b && inputid.product() == 0x1101 {
+        log_info("Applying retrogame_joypad quirk");
+        return Some(Box::new(retrogame_joypad));
     }
-    if has_key(&dev, Key::BTN_DPAD_LEFT) {
+    if has_key(dev, Key::BTN_DPAD_LEFT) {
         log_info("Applying generic_dac quirk");
-        return Some(generic_dac);
+        return Some(Box::new(generic_dac));
     }
     None
 }
 
-fn input_handler(tx: mpsc::Sender<InputEvent>, mut dev: Device) -> Result<()> {
+#[inline]
+fn is_trigger(axis: AbsoluteAxisType) -> bool {
+    matches!(axis, AbsoluteAxisType::ABS_Z | AbsoluteAxisType::ABS_RZ | AbsoluteAxisType::ABS_GAS | AbsoluteAxisType::ABS_BRAKE)
+}
+
+// keyboards and some pads report held keys with a value of 2
+#[inline]
+fn is_autorepeat(ev: &InputEvent) -> bool {
+    ev.event_type() == EventType::KEY && ev.value() == 2
+}
+
+// nothing downstream knows what to do with those, no matter the profile, so they go right where they're read
+fn without_autorepeat(events: impl IntoIterator<Item = InputEvent>) -> impl Iterator<Item = InputEvent> {
+    events.into_iter().filter(|ev| !is_autorepeat(ev))
+}
+
+// multitouch axes of hybrid devices have no place on a gamepad
+#[inline]
+fn is_mt_axis(axis: AbsoluteAxisType) -> bool {
+    axis.0 >= AbsoluteAxisType::ABS_MT_SLOT.0
+}
+
+// log every nth unhandled event, so discovering what a device sends doesn't flood the log
+#[inline]
+fn should_sample(seen: u64, every: u64) -> bool {
+    every != 0 && seen.is_multiple_of(every)
+}
+
+fn output_range(axis: AbsoluteAxisType, config: &Config) -> (i32, i32) {
+    if is_trigger(axis) {
+        (MIN_OUT_TRIG, config.trigger_max.max(1))
+    } else {
+        config.analog_range()
+    }
+}
+
+// maps the source range linearly onto the output one, rounding to the nearest step. sticks are scaled
+// either side of their center separately, so one at rest lands on exactly 0 whatever its range
+fn scale_axis(value: i32, src: &AbsInfo, out_min: i32, out_max: i32) -> i32 {
+    if src.maximum() <= src.minimum() {
+        return value.clamp(out_min, out_max);
+    }
+    let (min, max) = (src.minimum() as f64, src.maximum() as f64);
+    let scaled = if out_min < 0 && out_max > 0 {
+        let center = (min + max) / 2.0;
+        match value as f64 - center {
+            offset if offset >= 0.0 => offset / (max - center) * out_max as f64,
+            offset => offset / (center - min) * -(out_min as f64),
+        }
+    } else {
+        let pos = (value as f64 - min) / (max - min);
+        out_min as f64 + pos * (out_max as f64 - out_min as f64)
+    };
+    (scaled.round() as i32).clamp(out_min, out_max)
+}
+
+// some dpads report as hats of a stick's size or as outright analog axes, past half way
+// towards either end counts as pressed. a regular -1..1 hat comes out as it went in
+fn scale_hat(value: i32, src: Option<&AbsInfo>) -> i32 {
+    let (min, max) = match src {
+        Some(src) if src.maximum() > src.minimum() => (src.minimum(), src.maximum()),
+        _ => return value.clamp(MIN_OUT_HAT, MAX_OUT_HAT),
+    };
+    let center = (min as f64 + max as f64) / 2.0;
+    let threshold = (max as f64 - min as f64) / 4.0;
+    let offset = value as f64 - center;
+    if offset > threshold {
+        MAX_OUT_HAT
+    } else if offset < -threshold {
+        MIN_OUT_HAT
+    } else {
+        0
+    }
+}
+
+// a raw value from an axis with range `src` onto the output range of `target`, which a remap may have made another axis
+fn scale_onto(value: i32, src: Option<&AbsInfo>, target: AbsoluteAxisType, config: &Config) -> i32 {
+    if matches!(target, AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y) {
+        return scale_hat(value, src);
+    }
+    let (out_min, out_max) = output_range(target, config);
+    match src {
+        Some(src) => scale_axis(value, src, out_min, out_max),
+        None => value.clamp(out_min, out_max),
+    }
+}
+
+// the range every axis of the device reports, to be scaled onto ours, along with the triggers
+// that need flipping around the middle of their range, as min + max
+fn source_ranges(axes: &[(AbsoluteAxisType, AbsInfo)], device: Option<&config::DeviceConfig>) -> (HashMap<u16, AbsInfo>, HashMap<u16, i32>) {
+    let invert_triggers = device.and_then(|cfg| cfg.invert_triggers);
+    let bidirectional_triggers = device.and_then(|cfg| cfg.bidirectional_triggers);
+
+    // inverted ones rest at their maximum, but so does a trigger that happens to be held while plugging
+    // the pad in. an inverted pad has all of its triggers that way round, and 0/1 ones are just buttons
+    let mut triggers = axes.iter().filter(|(axis, _)| is_trigger(*axis)).map(|(_, info)| info).peekable();
+    let resting_at_max = triggers.peek().is_some()
+        && triggers.all(|info| info.maximum() - info.minimum() > 1 && info.value() == info.maximum());
+
+    let mut src_ranges = HashMap::new();
+    let mut inverted_triggers = HashMap::new();
+    for (axis, info) in axes.iter().copied() {
+        if is_trigger(axis) && invert_triggers.unwrap_or(resting_at_max) {
+            log_info(format!("Treating axis {} as an inverted trigger", axis.0));
+            inverted_triggers.insert(axis.0, info.minimum() + info.maximum());
+        }
+
+        // triggers going negative use their whole range unless told to only use the positive half
+        let minimum = match is_trigger(axis) && info.minimum() < 0 {
+            true if bidirectional_triggers == Some(false) => 0,
+            true => {
+                log_info(format!("Treating axis {} as a bidirectional trigger", axis.0));
+                info.minimum()
+            },
+            false => info.minimum(),
+        };
+        src_ranges.insert(axis.0, AbsInfo::new(info.value(), minimum, info.maximum(), info.fuzz(), info.flat(), info.resolution()));
+    }
+    (src_ranges, inverted_triggers)
+}
+
+// newer steam clients use valve's own ids for their virtual pads, older ones impersonate a wired
+// 360 pad but being uinput devices they lack the physical path every real one has
+fn is_steam_virtual(dev: &Device) -> bool {
+    is_steam_id(dev.input_id(), dev.physical_path().unwrap_or(""))
+}
+
+fn is_steam_id(id: InputId, phys: &str) -> bool {
+    let valve = id.vendor() == 0x28de && id.product() == 0x11ff;
+    let fake_xbox = id.vendor() == 0x045e && id.product() == 0x028e && phys.is_empty();
+    valve || fake_xbox
+}
+
+fn is_useful(dev: &Device) -> bool {
     let mut useful = false;
 
     // gamepads
-    if has_key(&dev, Key::BTN_SOUTH) {
+    if has_key(dev, Key::BTN_SOUTH) {
         useful = true;
     }
 
-    // touchscreens
-    if has_key(&dev, Key::BTN_TOUCH) {
+    // touchscreens, not all of them bother with BTN_TOUCH
+    let has_mt = dev.supported_absolute_axes().is_some_and(|axes| axes.contains(AbsoluteAxisType::ABS_MT_POSITION_X));
+    if has_key(dev, Key::BTN_TOUCH) || (has_mt && dev.properties().contains(PropType::DIRECT)) {
         useful = false;
     }
 
-    // rinputer
-    if dev.input_id().version() == 0x2137 {
+    // other rinputer instances, our own nodes are already filtered out by path
+    if is_rinputer(dev.input_id()) {
         useful = false;
     }
 
-    // steam input, note the space
-    if dev.name().unwrap_or("Microsoft X-Box 360 pad ").starts_with("Microsoft X-Box 360 pad ") {
+    // steam input
+    if is_steam_virtual(dev) {
         useful = false;
     }
 
-    if !useful {
+    useful
+}
+
+#[inline]
+fn is_rinputer(id: InputId) -> bool {
+    id.version() == RINPUTER_VERSION
+}
+
+// right after boot logind or a display manager may hold devices for a moment, back off and retry
+fn grab_retry_delay(attempt: u32, since_start: Duration, boot_window: Duration) -> Option<Duration> {
+    if since_start >= boot_window {
+        return None;
+    }
+    Some(Duration::from_millis(50 << attempt.min(5)))
+}
+
+// good enough to recognize a controller that dropped off and came back on a new node,
+// one that switched modes and came back with other buttons or axes is a new device
+fn device_identity(dev: &Device) -> String {
+    let id = dev.input_id();
+    let keys: Vec<u16> = dev.supported_keys().map(|keys| keys.iter().map(|key| key.0).collect()).unwrap_or_default();
+    let axes: Vec<u16> = dev.supported_absolute_axes().map(|axes| axes.iter().map(|axis| axis.0).collect()).unwrap_or_default();
+    identity(id, dev.name().unwrap_or(""), dev.unique_name().unwrap_or(""), &keys, &axes)
+}
+
+fn identity(id: InputId, name: &str, uniq: &str, keys: &[u16], axes: &[u16]) -> String {
+    format!("{:04x}:{:04x} {} {} {:?} {:?}", id.vendor(), id.product(), name, uniq, keys, axes)
+}
+
+// where the device ranks in hat_priority, unlisted ones come last
+fn hat_priority(dev: &Device, config: &Config) -> usize {
+    let id = dev.input_id();
+    config.hat_priority.iter()
+        .position(|entry| config::parse_vid_pid(entry) == Some((id.vendor(), id.product())))
+        .unwrap_or(usize::MAX)
+}
+
+// the modifier layer's modifier key, if it lives on this device
+fn modifier_key(dev: &Device, config: &Config) -> Option<Key> {
+    config.modifier_layer.as_ref()
+        .filter(|layer| config::parse_vid_pid(&layer.device) == Some((dev.input_id().vendor(), dev.input_id().product())))
+        .and_then(|layer| remap::parse_key_alias(&layer.key))
+}
+
+fn input_handler(tx: mpsc::Sender<Frame>, path: PathBuf, mut dev: Device, config: Arc<Config>, shared: Arc<Shared>) -> Result<()> {
+    let tx = FrameSender::new(tx, shared.next_source.fetch_add(1, Ordering::Relaxed), hat_priority(&dev, &config));
+    // --allow and --deny trump the heuristics, denied devices aren't even companions
+    let overridden = config.device_override(dev.input_id());
+    if !overridden.unwrap_or_else(|| is_useful(&dev)) {
+        let companion_keys = companion_keys(&config);
+        if overridden.is_none() && companion_keys.keys().any(|key| has_key(&dev, Key(*key))) && is_companion(&dev) {
+            // someone else has it, stays marked as handled until the node goes away like any other
+            if config.grab_companions && dev.grab().is_err() {
+                log_debug(format!("Failed to grab companion node {}", dev.name().unwrap_or("<invalid name>")));
+                return Ok(());
+            }
+            let ret = companion_handler(tx, dev, companion_keys, &config, &shared);
+            shared.handled.lock().unwrap().remove(&path);
+            return ret;
+        }
+        // stays marked as handled until the node goes away
         return Ok(());
     }
 
-    match dev.grab() {
-        Ok(()) => log_debug(format!("Device {} deemed useful", dev.name().unwrap_or("<invalid name>"))),
-        Err(_) => return Ok(()), // fail silently in case someone else grabbed it before us
+    let name = config.device_name(dev.input_id(), &path)
+        .unwrap_or(dev.name().unwrap_or("<invalid name>"))
+        .to_string();
+
+    let boot_window = Duration::from_secs(config.boot_grab_window_secs);
+    let mut attempt = 0;
+    loop {
+        match dev.grab() {
+            Ok(()) => {
+                log_debug(format!("Device {} deemed useful", name));
+                shared.grabbed.fetch_add(1, Ordering::Relaxed);
+                break;
+            },
+            Err(_) => match grab_retry_delay(attempt, shared.started.elapsed(), boot_window) {
+                Some(delay) => thread::sleep(delay),
+                None => {
+                    // fail silently in case someone else grabbed it before us, and try again next scan
+                    shared.handled.lock().unwrap().remove(&path);
+                    return Ok(());
+                },
+            },
+        }
+        attempt += 1;
+    }
+
+    let identity = device_identity(&dev);
+    let pinned = config.device(dev.input_id(), &path)
+        .and_then(|device| device.player)
+        .filter(|_| config.pads > 1)
+        .map(|player| player.saturating_sub(1));
+    let claim = match shared.slots.lock().unwrap().claim(&identity, pinned) {
+        Some(claim) => claim,
+        None => {
+            // stays marked as handled until the node goes away
+            log_info(format!("Device {} is pinned to player {} which is taken already, ignoring it", name, pinned.unwrap_or(0) + 1));
+            let _ = dev.ungrab();
+            shared.grabbed.fetch_sub(1, Ordering::Relaxed);
+            return Ok(());
+        },
     };
 
+    // pick up where we left off if this is a device coming back within its grace period
+    let (mut held, tx) = match shared.disconnected.lock().unwrap().remove(&identity) {
+        Some((held, source)) => {
+            log_info(format!("Device {} reconnected, resuming", name));
+            (held, tx.resuming(source))
+        },
+        None => (HeldState::default(), tx),
+    };
+    let tx = tx.for_pad(claim.pad);
+    if config.pads > 1 {
+        log_info(format!("Device {} is player {}", name, claim.pad + 1));
+    }
 
-    let mut abs_minimums: [i32; 6] = [0; 6];
-    let mut abs_maximums: [i32; 6] = [0; 6];
+    grab_state_changed(&config, &name, true);
+    if config.rumble {
+        shared.rumblers.register(&path, &name, claim.pad);
+    }
+    let ret = handle_events(&tx, &mut dev, &path, &name, &config, &shared, &mut held);
+    shared.rumblers.unregister(&path);
+    grab_state_changed(&config, &name, false);
+    shared.handled.lock().unwrap().remove(&path);
+    // don't leave the layer stuck on if the pedal goes away mid press
+    if modifier_key(&dev, &config).is_some() {
+        shared.modifier.store(false, Ordering::Relaxed);
+    }
 
-    if let Ok(absinfo) = dev.get_abs_state() {
-        for axis in 0..6 {
-            abs_minimums[axis] = absinfo[axis].minimum;
-            abs_maximums[axis] = absinfo[axis].maximum;
-        }
+    // let go whatever happened, a device that went away may be back on this very node
+    let _ = dev.ungrab();
+    shared.grabbed.fetch_sub(1, Ordering::Relaxed);
+
+    match ret {
+        // main is gone, there's nobody left to release anything to
+        Err(e) if is_shutdown(&e) => {
+            log_debug(format!("Output went away, letting go of {}", name));
+            shared.slots.lock().unwrap().release(&claim);
+            Ok(())
+        },
+        Err(e) => {
+            log_debug(format!("Device {} disconnected: {}", name, e));
+            drop(dev);
+            // keep the player slot for it meanwhile
+            shared.slots.lock().unwrap().linger(&claim);
+            wait_for_reconnect(&tx, &shared, identity, held, Duration::from_millis(config.disconnect_grace_ms));
+            shared.slots.lock().unwrap().release(&claim);
+            Err(e)
+        },
+        Ok(()) => {
+            log_debug(format!("Shutting down, letting go of {}", name));
+            shared.slots.lock().unwrap().release(&claim);
+            Ok(())
+        },
     }
+}
 
-    let abs_multipliers_min = abs_minimums.into_iter()
-        .enumerate()
-        .map(|(i, v)| {
-            let cmp_against = if i == AbsoluteAxisType::ABS_Z.0 as usize || i == AbsoluteAxisType::ABS_RZ.0 as usize {
-                MIN_OUT_TRIG
-            } else {
-                MIN_OUT_ANALOG
-            };
-            if (v - cmp_against).abs() < 100 || v == 0 {
-                1
-            } else {
-                cmp_against / v
-            }
+fn companion_keys(config: &Config) -> HashMap<u16, Key> {
+    config.companion_keys.iter()
+        .filter_map(|(from, to)| match (remap::parse_key_alias(from), remap::parse_key_alias(to)) {
+            (Some(from), Some(to)) => Some((from.0, to)),
+            _ => {
+                log_info(format!("Ignoring companion key mapping {} -> {}", from, to));
+                None
+            },
         })
-        .collect::<Vec<i32>>();
-
-    let abs_multipliers_max = abs_maximums.into_iter()
-        .enumerate()
-        .map(|(i, v)| {
-            let cmp_against = if i == AbsoluteAxisType::ABS_Z.0 as usize || i == AbsoluteAxisType::ABS_RZ.0 as usize {
-                MAX_OUT_TRIG
-            } else {
-                MAX_OUT_ANALOG
-            };
-            if (v - cmp_against).abs() < 100 || v == 0 {
-                1
-            } else {
-                cmp_against / v
+        .collect()
+}
+
+// keyboard nodes hanging off the same physical device as a gamepad, e.g. the keys of a usb pad's keyboard interface,
+// or built in ones next to a built in gamepad, like the volume keys on the gpio-keys node of a handheld
+fn is_companion(dev: &Device) -> bool {
+    let pads: Vec<(Option<String>, BusType)> = evdev::enumerate()
+        .filter(|(_, other)| has_key(other, Key::BTN_SOUTH) && !is_rinputer(other.input_id()))
+        .map(|(_, other)| (other.physical_path().map(str::to_string), other.input_id().bus_type()))
+        .collect();
+    companion_of(dev.physical_path(), dev.input_id().bus_type(), &pads)
+}
+
+fn companion_of(phys: Option<&str>, bus: BusType, pads: &[(Option<String>, BusType)]) -> bool {
+    fn parent(phys: &str) -> Option<&str> {
+        phys.rsplit_once('/').map(|(parent, _)| parent)
+    }
+
+    let parent_of_node = phys.and_then(parent);
+    pads.iter().any(|(pad_phys, pad_bus)| {
+        let same_device = parent_of_node.is_some() && pad_phys.as_deref().and_then(parent) == parent_of_node;
+        // whatever is wired up inside the handheld sits on the host bus, or at least on neither usb nor bluetooth
+        let both_built_in = bus == BusType::BUS_HOST && ![BusType::BUS_USB, BusType::BUS_BLUETOOTH].contains(pad_bus);
+        same_device || both_built_in
+    })
+}
+
+// the pad button a companion key goes out as, if it's one of the configured ones
+fn companion_event(keys: &HashMap<u16, Key>, ev: &InputEvent) -> Option<InputEvent> {
+    match ev.kind() {
+        InputEventKind::Key(key) => keys.get(&key.0)
+            .map(|to| InputEvent::new(EventType::KEY, to.0, ev.value())),
+        _ => None,
+    }
+}
+
+// only the configured keys make it to the pad, everything else on the node is left alone
+// the node is grabbed already if grab_companions is set
+fn companion_handler(tx: FrameSender, mut dev: Device, keys: HashMap<u16, Key>, config: &Config, shared: &Shared) -> Result<()> {
+    if config.grab_companions {
+        shared.grabbed.fetch_add(1, Ordering::Relaxed);
+    }
+    log_debug(format!("Reading keys of companion node {}", dev.name().unwrap_or("<invalid name>")));
+
+    let mut held = HeldState::default();
+    let mut frame = Vec::new();
+    let ret = (|| -> Result<()> {
+        while shutdown::wait_readable(&dev)? {
+            for ev in without_autorepeat(dev.fetch_events()?) {
+                match ev.kind() {
+                    InputEventKind::Synchronization(Synchronization::SYN_REPORT) if !frame.is_empty() => {
+                        tx.send(std::mem::take(&mut frame))?;
+                    },
+                    _ => if let Some(ev) = companion_event(&keys, &ev) {
+                        held.update(&ev);
+                        frame.push(ev);
+                    },
+                }
             }
+        }
+        Ok(())
+    })();
+
+    let _ = reset_to_neutral(&tx, &mut held);
+    if config.grab_companions {
+        let _ = dev.ungrab();
+        shared.grabbed.fetch_sub(1, Ordering::Relaxed);
+    }
+    match ret {
+        Err(e) if is_shutdown(&e) => Ok(()),
+        ret => ret,
+    }
+}
+
+// the receiving end in main only drops when the process is going down
+fn is_shutdown(err: &anyhow::Error) -> bool {
+    err.is::<mpsc::SendError<Frame>>()
+}
+
+// releases everything the device held down, unless it comes back before `grace` runs out
+fn wait_for_reconnect(tx: &FrameSender, shared: &Shared, identity: String, held: HeldState, grace: Duration) {
+    let since = Instant::now();
+    shared.disconnected.lock().unwrap().insert(identity.clone(), (held, tx.source()));
+
+    while since.elapsed() < grace {
+        thread::sleep(Duration::from_millis(100));
+        if !shared.disconnected.lock().unwrap().contains_key(&identity) {
+            return;
+        }
+    }
+
+    if let Some((mut held, _)) = shared.disconnected.lock().unwrap().remove(&identity) {
+        let _ = reset_to_neutral(tx, &mut held);
+    }
+}
+
+// every path that stops forwarding a device goes through here, so nothing is left pressed or deflected
+fn reset_to_neutral(tx: &FrameSender, held: &mut HeldState) -> Result<(), mpsc::SendError<Frame>> {
+    let frame = held.reset();
+    if !frame.is_empty() {
+        tx.send(frame)?;
+    }
+    Ok(())
+}
+
+fn grab_status(name: &str, grabbed: bool) -> String {
+    format!("{} {}", if grabbed { "Grabbed" } else { "Released" }, name)
+}
+
+// rings the terminal bell for headless setups
+fn notification(status: &str) -> String {
+    format!("\x07rinputer4_5: {}", status)
+}
+
+fn grab_state_changed(config: &Config, name: &str, grabbed: bool) {
+    let status = grab_status(name, grabbed);
+    if config.notify {
+        println!("{}", notification(&status));
+    }
+    log_info(status);
+
+    let hook = if grabbed { &config.on_grab } else { &config.on_release };
+    if let Some(cmd) = hook {
+        run_hook(cmd, name);
+    }
+}
+
+// hooks must never take the handler down, so they only ever get logged
+fn run_hook(cmd: &str, name: &str) {
+    match Command::new(cmd).arg(name).spawn() {
+        Ok(mut child) => {
+            let cmd = cmd.to_string();
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => log_info(format!("Hook {} failed with {}", cmd, status)),
+                Err(e) => log_info(format!("Failed to wait for hook {}: {}", cmd, e)),
+                Ok(_) => (),
+            });
+        },
+        Err(e) => log_info(format!("Failed to run hook {}: {}", cmd, e)),
+    }
+}
+
+fn relay_raw(frame: &mut PendingFrame, ev: InputEvent, held: &mut HeldState, now: Instant) {
+    match ev.kind() {
+        // raw axes don't necessarily rest at 0, only keys can be released safely
+        InputEventKind::Key(_) => {
+            held.update(&ev);
+            frame.push(ev, now);
+        },
+        InputEventKind::AbsAxis(_) => frame.push(ev, now),
+        _ => (),
+    }
+}
+
+fn parse_chord(names: &[String], what: &str) -> Vec<Key> {
+    names.iter()
+        .filter_map(|name| {
+            let key = remap::parse_key_alias(name);
+            if key.is_none() {
+                log_info(format!("Ignoring unknown key {} in {}", name, what));
+            }
+            key
         })
-        .collect::<Vec<i32>>();
+        .collect()
+}
+
+fn handle_events(tx: &FrameSender, dev: &mut Device, path: &Path, name: &str, config: &Config, shared: &Shared, held: &mut HeldState) -> Result<()> {
+    let axes: Vec<(AbsoluteAxisType, AbsInfo)> = match (dev.supported_absolute_axes(), dev.get_abs_state()) {
+        (Some(axes), Ok(absinfo)) => axes.iter()
+            .map(|axis| {
+                let info = absinfo[axis.0 as usize];
+                (axis, AbsInfo::new(info.value, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution))
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let remap_fn = get_remap_fn(dev, path, config);
+    let mut pipeline = pipeline::Pipeline::new(config, config.device(dev.input_id(), path), &axes, remap_fn);
+
+    let mut cycle_chord = config.profile_cycle.as_ref()
+        .map(|cycle| Chord::new(parse_chord(&cycle.chord, "profile_cycle")));
+    let mut release_chord = config.release_chord.as_ref()
+        .map(|chord| ReleaseChord::new(parse_chord(&chord.keys, "release_chord"), chord.mode));
+    let mut released = false;
+
+    let modifier = modifier_key(dev, config);
+
+    let mut rate_counter = match config.rate_log_interval_secs {
+        0 => None,
+        secs => Some(RateCounter::new(Duration::from_secs(secs))),
+    };
+
+    let mut unknown_seen = 0;
+    let mut log_unknown = |ev: &InputEvent| {
+        if should_sample(unknown_seen, config.log_unknown_every) {
+            log_info(format!("{}: unhandled event type {} code {} value {}", name, ev.event_type().0, ev.code(), ev.value()));
+        }
+        unknown_seen += 1;
+    };
 
-    let remap_fn = get_remap_fn(&mut dev);
+    let mut frame = PendingFrame::new(FRAME_DEADLINE);
 
     loop {
-        for mut ev in dev.fetch_events()? {
-            match ev.kind() {
-                InputEventKind::AbsAxis(axis) => {
-                    let val = match axis {
-                        AbsoluteAxisType::ABS_HAT0Y => ev.value(), // assuming it's always between -1
-                        AbsoluteAxisType::ABS_HAT0X => ev.value(), // and 1
-                        _ => if ev.value() < 0 {
-                            ev.value() * abs_multipliers_min[axis.0 as usize]
-                        } else {
-                            ev.value() * abs_multipliers_max[axis.0 as usize]
-                        },
-                    };
-                    tx.send(InputEvent::new(ev.event_type(), ev.code(), val))?;
+        let now = Instant::now();
+        let timeout = [pipeline.due_in(now), frame.due_in(now)].into_iter().flatten().min();
+        match shutdown::wait_readable_for(dev, timeout)? {
+            shutdown::Wait::Readable => (),
+            shutdown::Wait::Timeout => {
+                let now = Instant::now();
+                let partial = !frame.is_empty();
+                // nothing came in, but smoothed axes are still catching up with where the device left them
+                let stepped = pipeline.poll(now, config);
+                if !released {
+                    for ev in stepped {
+                        held.update(&ev);
+                        frame.push(ev, now);
+                    }
+                }
+                // those go out on their own, unless there's a frame still coming in that they'd cut in two
+                if !frame.is_empty() && (!partial || frame.is_overdue(now)) {
+                    tx.send(frame.take())?;
+                }
+                continue;
+            },
+            shutdown::Wait::Shutdown => break,
+        }
+
+        // collected up front, some of the handling below needs the device itself
+        let events: Vec<InputEvent> = dev.fetch_events()?.collect();
+        let batch = events.len() as u64;
+        let now = Instant::now();
+        for ev in without_autorepeat(events) {
+            if let InputEventKind::Synchronization(Synchronization::SYN_REPORT) = ev.kind() {
+                if !frame.is_empty() {
+                    tx.send(frame.take())?;
+                }
+                continue;
+            }
+
+            match release_chord.as_mut().and_then(|chord| chord.update(&ev)) {
+                Some(true) => {
+                    log_info(format!("Releasing {}", name));
+                    // whatever is pending is covered by the neutral frame already
+                    frame.clear();
+                    reset_to_neutral(tx, held)?;
+                    pipeline.reset();
+                    // the grab's gone but events still reach us, so we can notice the chord again
+                    let _ = dev.ungrab();
+                    released = true;
+                },
+                Some(false) => {
+                    log_info(format!("Grabbing {} again", name));
+                    dev.grab()?;
+                    released = false;
+                    continue;
+                },
+                None => (),
+            }
+            if released || release_chord.as_mut().is_some_and(|chord| chord.swallows(&ev)) {
+                continue;
+            }
+
+            // a released device shouldn't be switching anyone's profile
+            if cycle_chord.as_mut().is_some_and(|chord| chord.update(&ev)) {
+                shared.profile.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // the flat debug mode relays into whatever profile is active, to rule out scaling and remaps,
+            // not even the modifier of the modifier layer is held back
+            if config.flat || active_profile(config, shared) == Profile::Passthrough {
+                match ev.kind() {
+                    InputEventKind::Key(_) | InputEventKind::AbsAxis(_) => relay_raw(&mut frame, ev, held, now),
+                    InputEventKind::Synchronization(_) => (),
+                    _ => log_unknown(&ev),
                 }
-                InputEventKind::Key(_) => {
-                    if let Some(actual_remap_fn) = remap_fn {
-                        actual_remap_fn(&mut ev, tx.clone());
+                continue;
+            }
+
+            if let (Some(modifier), InputEventKind::Key(key)) = (modifier, ev.kind()) {
+                if key == modifier {
+                    shared.modifier.store(ev.value() != 0, Ordering::Relaxed);
+                    continue;
+                }
+            }
+
+            match ev.kind() {
+                InputEventKind::AbsAxis(_) | InputEventKind::Key(_) => {
+                    for ev in pipeline.process(ev, shared.modifier.load(Ordering::Relaxed), config, now) {
+                        held.update(&ev);
+                        frame.push(ev, now);
                     }
-                    tx.send(ev)?;
                 },
-                _ => (),
+                InputEventKind::Synchronization(_) => (),
+                _ => log_unknown(&ev),
             }
         }
+
+        // a device that never sends SYN_REPORT but keeps on sending would never wait long enough to time out
+        if frame.is_overdue(now) {
+            tx.send(frame.take())?;
+        }
+
+        if let Some(rate) = rate_counter.as_mut().and_then(|counter| counter.tick(batch, now)) {
+            log_info(format!("{}: {:.1} events/s", name, rate));
+        }
+    }
+    Ok(())
+}
+
+fn register_own_nodes(dev: &mut VirtualDevice, shared: &Shared) -> Result<Vec<PathBuf>> {
+    let mut own = shared.own_nodes.lock().unwrap();
+    let mut nodes = Vec::new();
+    for node in dev.enumerate_dev_nodes_blocking()? {
+        let node = node?;
+        log_debug(format!("Created virtual device node {}", node.display()));
+        own.insert(node.clone());
+        nodes.push(node);
     }
+    Ok(nodes)
 }
 
-fn indev_watcher(tx: mpsc::Sender<InputEvent>) {
+fn sequences(config: &Config) -> Vec<Sequence> {
+    config.sequences.iter()
+        .filter_map(|seq| {
+            let keys = parse_chord(&seq.keys, "sequence");
+            let output = remap::parse_key_alias(&seq.output);
+            if keys.len() != seq.keys.len() || output.is_none() {
+                log_info(format!("Ignoring sequence {:?}", seq.keys));
+                return None;
+            }
+            Some(Sequence::new(keys, output?, Duration::from_millis(seq.window_ms), seq.swallow))
+        })
+        .collect()
+}
+
+fn combos(config: &Config) -> Vec<Combo> {
+    config.combos.iter()
+        .filter_map(|combo| {
+            let keys = parse_chord(&combo.keys, "combo");
+            let output = remap::parse_key_alias(&combo.output);
+            if keys.len() != combo.keys.len() || keys.is_empty() || output.is_none() {
+                log_info(format!("Ignoring combo {:?}", combo.keys));
+                return None;
+            }
+            Some(Combo::new(keys, output?, Duration::from_millis(combo.window_ms)))
+        })
+        .collect()
+}
+
+// joydev hangs jsN off the input device in sysfs, with axes ordered by code just like xpad's
+fn js_node(syspath: &Path) -> Option<PathBuf> {
+    fs::read_dir(syspath).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .find(|name| name.to_string_lossy().starts_with("js"))
+        .map(|name| Path::new("/dev/input").join(name))
+}
+
+// our own virtual devices, whatever kind they are, and anything the config keeps us away from
+fn skip_node(path: &Path, own_nodes: &HashSet<PathBuf>, config: &Config) -> bool {
+    own_nodes.contains(path) || config.is_excluded(path)
+}
+
+fn indev_watcher(tx: mpsc::Sender<Frame>, shared: Arc<Shared>, config: Arc<Config>) {
     loop {
-        for device in evdev::enumerate() {
+        let devices: Vec<(PathBuf, Device)> = evdev::enumerate().collect();
+        // forget nodes that are gone, the kernel hands the same eventN out to the next device
+        shared.handled.lock().unwrap().retain(|handled| devices.iter().any(|(path, _)| path == handled));
+
+        for (path, device) in devices {
+            if skip_node(&path, &shared.own_nodes.lock().unwrap(), &config) {
+                continue;
+            }
+            if !shared.handled.lock().unwrap().insert(path.clone()) {
+                continue;
+            }
             let new_tx = tx.clone();
-            thread::spawn(move || input_handler(new_tx, device.1));
+            let config = config.clone();
+            let shared = shared.clone();
+            thread::spawn(move || input_handler(new_tx, path, device, config, shared));
         }
         thread::sleep(Duration::from_secs(1));
     }
 }
 
-fn main() -> Result<()> {
+fn xbox_input_id() -> InputId {
+    InputId::new(BusType::BUS_USB, 0x045e, 0x028e, RINPUTER_VERSION)
+}
+
+fn build_xbox_device(config: &Config) -> Result<VirtualDevice> {
+    let (min_out_analog, max_out_analog) = config.analog_range();
+
     let mut keys = evdev::AttributeSet::<Key>::new();
     keys.insert(Key::BTN_SOUTH);
     keys.insert(Key::BTN_EAST);
@@ -214,16 +936,29 @@ fn main() -> Result<()> {
     keys.insert(Key::BTN_MODE);
     keys.insert(Key::BTN_THUMBL);
     keys.insert(Key::BTN_THUMBR);
+    for key in companion_keys(config).into_values() {
+        keys.insert(key);
+    }
+    for key in config.sequences.iter().filter_map(|seq| remap::parse_key_alias(&seq.output)) {
+        keys.insert(key);
+    }
+    for key in config.combos.iter().filter_map(|combo| remap::parse_key_alias(&combo.output)) {
+        keys.insert(key);
+    }
+    if config.two_stage_triggers.is_some() {
+        let (left, right) = (transform::TwoStage::LEFT, transform::TwoStage::RIGHT);
+        for key in [left.0, left.1, right.0, right.1] {
+            keys.insert(key);
+        }
+    }
 
-    let input_id = InputId::new(evdev::BusType::BUS_USB, 0x045e, 0x028e, 0x2137);
-
-    let abs_analogs = AbsInfo::new(0, MIN_OUT_ANALOG, MAX_OUT_ANALOG, 16, 256, 0);
+    let abs_analogs = AbsInfo::new(0, min_out_analog, max_out_analog, 16, 256, 0);
     let abs_x = UinputAbsSetup::new(AbsoluteAxisType::ABS_X, abs_analogs);
     let abs_y = UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, abs_analogs);
     let abs_rx = UinputAbsSetup::new(AbsoluteAxisType::ABS_RX, abs_analogs);
     let abs_ry = UinputAbsSetup::new(AbsoluteAxisType::ABS_RY, abs_analogs);
 
-    let abs_triggers = AbsInfo::new(0, MIN_OUT_TRIG, MAX_OUT_TRIG, 0, 0, 0);
+    let abs_triggers = AbsInfo::new(0, MIN_OUT_TRIG, max_out_trig(), 0, 0, 0);
     let abs_z = UinputAbsSetup::new(AbsoluteAxisType::ABS_Z, abs_triggers);
     let abs_rz = UinputAbsSetup::new(AbsoluteAxisType::ABS_RZ, abs_triggers);
 
@@ -231,30 +966,652 @@ fn main() -> Result<()> {
     let abs_hat_x = UinputAbsSetup::new(AbsoluteAxisType::ABS_HAT0X, abs_hat);
     let abs_hat_y = UinputAbsSetup::new(AbsoluteAxisType::ABS_HAT0Y, abs_hat);
 
-    let mut uhandle = VirtualDeviceBuilder::new()
+    let builder = VirtualDeviceBuilder::new()
         .context("Failed to create instance of evdev::VirtualDeviceBuilder")?
         .name(b"Microsoft X-Box 360 pad")
-        .input_id(input_id)
+        .input_id(xbox_input_id())
         .with_keys(&keys)?
         .with_absolute_axis(&abs_x)?
         .with_absolute_axis(&abs_y)?
         .with_absolute_axis(&abs_rx)?
-        .with_absolute_axis(&abs_ry)?
-        .with_absolute_axis(&abs_z)?
-        .with_absolute_axis(&abs_rz)?
-        .with_absolute_axis(&abs_hat_x)?
-        .with_absolute_axis(&abs_hat_y)?
-        .build()
+        .with_absolute_axis(&abs_ry)?;
+
+    let builder = if config.combined_triggers {
+        let abs_combined = AbsInfo::new(0, -max_out_trig(), max_out_trig(), 0, 0, 0);
+        builder.with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Z, abs_combined))?
+    } else {
+        builder.with_absolute_axis(&abs_z)?
+            .with_absolute_axis(&abs_rz)?
+    };
+
+    let builder = builder.with_absolute_axis(&abs_hat_x)?
+        .with_absolute_axis(&abs_hat_y)?;
+
+    let builder = if config.rumble {
+        let mut ff = evdev::AttributeSet::<evdev::FFEffectType>::new();
+        ff.insert(evdev::FFEffectType::FF_RUMBLE);
+        builder.with_ff(&ff)?
+            .with_ff_effects_max(rumble::MAX_EFFECTS)
+    } else {
+        builder
+    };
+
+    let uhandle = builder.build()
         .context("Failed to create uinput device")?;
+    if config.rumble {
+        rumble::set_nonblocking(&uhandle)?;
+    }
+    Ok(uhandle)
+}
+
+fn build_device(profile: Profile, config: &Config) -> Result<VirtualDevice> {
+    match profile {
+        Profile::Xbox => build_xbox_device(config),
+        Profile::Passthrough => {
+            let mut caps = passthrough::Capabilities::new();
+            for (path, dev) in evdev::enumerate() {
+                if config.device_override(dev.input_id()).unwrap_or_else(|| is_useful(&dev)) && !config.is_excluded(&path) {
+                    caps.merge(&dev);
+                }
+            }
+            passthrough::build_device(&caps)
+        },
+    }
+}
+
+// which pads to create and which to remove so there's one for each claimed slot, the first one always stays
+fn pad_changes(existing: &[usize], claimed: &[usize]) -> (Vec<usize>, Vec<usize>) {
+    let create = claimed.iter()
+        .filter(|pad| !existing.contains(pad))
+        .copied()
+        .collect();
+    let retire = existing.iter()
+        .filter(|pad| **pad != 0 && !claimed.contains(pad))
+        .copied()
+        .collect();
+    (create, retire)
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("--dump") {
+        args.next();
+        return dump::run(args);
+    }
+
+    shutdown::install().context("Failed to install signal handlers")?;
+    let mut config = Config::load()?;
+    config.apply_args(args)?;
+    let config = Arc::new(config);
+    MAX_OUT_TRIG.store(config.trigger_max.max(1), Ordering::Relaxed);
+    let shared = Arc::new(Shared::new(&config));
+
+    // start the cycle at the configured profile
+    if let Some(cycle) = &config.profile_cycle {
+        let start = cycle.profiles.iter().position(|p| *p == config.profile).unwrap_or(0);
+        shared.profile.store(start, Ordering::Relaxed);
+    }
+
+    let mut profile = active_profile(&config, &shared);
+    // players past the first get their pad once a device claims their slot
+    let mut pads = vec![pad::Pad::new(0, profile, &config, &shared)?];
+
+    match pads[0].syspath().and_then(js_node) {
+        Some(js) => log_info(format!("Legacy joystick interface available at {}", js.display())),
+        None => log_info("No legacy joystick interface, is joydev loaded?"),
+    }
 
     log_debug("rinputer4_5 starting up");
 
     let (tx, rx) = mpsc::channel();
-    thread::spawn(move || indev_watcher(tx));
+    let watcher_shared = shared.clone();
+    let watcher_config = config.clone();
+    thread::spawn(move || indev_watcher(tx, watcher_shared, watcher_config));
+
+    let raw_axes = transform::parse_axes(&config.raw_axes);
+
+    let health_interval = match config.health_check_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let mut last_check = Instant::now();
+
+    loop {
+        let now = Instant::now();
+        let timeout = [
+            health_interval.map(|interval| (last_check + interval).saturating_duration_since(now)),
+            pads.iter().filter_map(|pad| pad.due_in(now)).min(),
+        ].into_iter().flatten().min()
+            .map(|due| due.max(Duration::from_millis(10)))
+            .unwrap_or(shutdown::POLL_INTERVAL)
+            // signals don't interrupt recv_timeout
+            .min(shutdown::POLL_INTERVAL);
+        let mut frame = match rx.recv_timeout(timeout) {
+            Ok(frame) => Some(frame),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if shutdown::requested() {
+            break;
+        }
+
+        let wanted = active_profile(&config, &shared);
+        if wanted != profile {
+            log_info(format!("Switching to {:?} profile", wanted));
+            for pad in pads.iter_mut() {
+                pad.rebuild(wanted, &config, &shared)?;
+            }
+            profile = wanted;
+        }
 
-    for ev in rx {
-        uhandle.emit(&[ev])?;
+        if let Some(interval) = health_interval {
+            if last_check.elapsed() >= interval {
+                last_check = Instant::now();
+                for pad in pads.iter_mut().filter(|pad| pad.is_missing()) {
+                    log_info(format!("Virtual pad {} went away, recreating it", pad.index() + 1));
+                    pad.rebuild(profile, &config, &shared)?;
+                }
+            }
+        }
+
+        // a pad for every player slot in use, so games see them all before anyone presses anything
+        let claimed = shared.slots.lock().unwrap().claimed();
+        let (create, retire) = pad_changes(&pads.iter().map(pad::Pad::index).collect::<Vec<_>>(), &claimed);
+        for index in retire {
+            log_info(format!("Removing virtual pad {}", index + 1));
+            let at = pads.iter().position(|pad| pad.index() == index).unwrap();
+            pads.swap_remove(at).retire(&shared);
+        }
+        for index in create {
+            log_info(format!("Creating virtual pad {}", index + 1));
+            pads.push(pad::Pad::new(index, profile, &config, &shared)?);
+        }
+
+        // every pad gets a look, the ones without a frame may have timers due.
+        // frames for a pad that's gone are dropped, there's no one left to play on it
+        for pad in pads.iter_mut() {
+            let mine = frame.take_if(|frame| frame.pad == pad.index());
+            pad.feed(mine, profile, &config, &raw_axes)?;
+        }
     }
 
+    // give the handlers a moment to let go of their devices before the virtual pads go,
+    // one still grabbed with no pad left would be dead to everyone meanwhile
+    log_info("Shutting down");
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while shared.grabbed.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(pads);
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remapped(quirk: fn(&mut InputEvent, &mut Vec<InputEvent>), key: Key, value: i32) -> (EventType, u16, i32) {
+        let mut ev = InputEvent::new(EventType::KEY, key.0, value);
+        quirk(&mut ev, &mut Vec::new());
+        (ev.event_type(), ev.code(), ev.value())
+    }
+
+    #[test]
+    fn retrogame_joypad_swaps_a_and_b() {
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_EAST, 1), (EventType::KEY, Key::BTN_SOUTH.0, 1));
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_SOUTH, 0), (EventType::KEY, Key::BTN_EAST.0, 0));
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_NORTH, 1), (EventType::KEY, Key::BTN_NORTH.0, 1));
+    }
+
+    #[test]
+    fn retrogame_joypad_function_key_is_guide() {
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_MODE, 1), (EventType::KEY, Key::BTN_MODE.0, 1));
+    }
+
+    #[test]
+    fn retrogame_joypad_dpad_and_triggers() {
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_DPAD_UP, 1), (EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0Y.0, -1));
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_DPAD_RIGHT, 1), (EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, 1));
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_DPAD_RIGHT, 0), (EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, 0));
+        assert_eq!(remapped(retrogame_joypad, Key::BTN_TR2, 1), (EventType::ABSOLUTE, AbsoluteAxisType::ABS_RZ.0, max_out_trig()));
+    }
+
+    #[test]
+    fn notifications_ring_the_bell() {
+        assert_eq!(notification(&grab_status("RG353 joypad", true)), "\x07rinputer4_5: Grabbed RG353 joypad");
+        assert_eq!(grab_status("RG353 joypad", false), "Released RG353 joypad");
+    }
+
+    #[test]
+    fn js_node_from_sysfs() {
+        let syspath = std::env::temp_dir().join(format!("rinputer-input-{}", std::process::id()));
+        fs::create_dir_all(syspath.join("event9")).unwrap();
+        assert_eq!(js_node(&syspath), None);
+        fs::create_dir_all(syspath.join("js2")).unwrap();
+        let found = js_node(&syspath);
+        fs::remove_dir_all(&syspath).unwrap();
+        assert_eq!(found, Some(PathBuf::from("/dev/input/js2")));
+    }
+
+    #[test]
+    fn remapped_axes_scale_onto_their_target() {
+        let config = Config::default();
+        let stick = AbsInfo::new(0, -32768, 32767, 0, 0, 0);
+        assert_eq!(scale_onto(32767, Some(&stick), AbsoluteAxisType::ABS_Z, &config), max_out_trig());
+        assert_eq!(scale_onto(-32768, Some(&stick), AbsoluteAxisType::ABS_Z, &config), 0);
+
+        let trigger = AbsInfo::new(0, 0, 255, 0, 0, 0);
+        assert_eq!(scale_onto(255, Some(&trigger), AbsoluteAxisType::ABS_X, &config), MAX_OUT_ANALOG);
+        assert_eq!(scale_onto(0, Some(&trigger), AbsoluteAxisType::ABS_X, &config), MIN_OUT_ANALOG);
+        assert_eq!(scale_onto(-32768, Some(&stick), AbsoluteAxisType::ABS_HAT0X, &config), MIN_OUT_HAT);
+    }
+
+    fn abs(min: i32, max: i32, value: i32) -> AbsInfo {
+        AbsInfo::new(value, min, max, 0, 0, 0)
+    }
+
+    #[test]
+    fn every_axis_gets_its_range() {
+        let axes = [
+            (AbsoluteAxisType::ABS_X, abs(0, 4095, 2048)),
+            (AbsoluteAxisType::ABS_RZ, abs(0, 255, 0)),
+            (AbsoluteAxisType::ABS_HAT0X, abs(-1, 1, 0)),
+            (AbsoluteAxisType::ABS_GAS, abs(0, 1023, 0)),
+            (AbsoluteAxisType::ABS_BRAKE, abs(0, 1023, 0)),
+        ];
+        let (ranges, inverted) = source_ranges(&axes, None);
+        assert_eq!(ranges.len(), axes.len());
+        assert_eq!(ranges[&AbsoluteAxisType::ABS_GAS.0].maximum(), 1023);
+        assert_eq!(ranges[&AbsoluteAxisType::ABS_X.0].maximum(), 4095);
+        assert!(inverted.is_empty());
+    }
+
+    #[test]
+    fn inverted_triggers_are_detected() {
+        let axes = [(AbsoluteAxisType::ABS_Z, abs(0, 1023, 1023)), (AbsoluteAxisType::ABS_RZ, abs(0, 1023, 1023))];
+        let (_, inverted) = source_ranges(&axes, None);
+        assert_eq!(inverted.get(&AbsoluteAxisType::ABS_Z.0), Some(&1023));
+        assert_eq!(inverted.get(&AbsoluteAxisType::ABS_RZ.0), Some(&1023));
+    }
+
+    #[test]
+    fn held_trigger_is_not_inverted() {
+        let axes = [(AbsoluteAxisType::ABS_Z, abs(0, 255, 255)), (AbsoluteAxisType::ABS_RZ, abs(0, 255, 0))];
+        let (_, inverted) = source_ranges(&axes, None);
+        assert!(inverted.is_empty());
+    }
+
+    #[test]
+    fn digital_triggers_are_not_inverted() {
+        let axes = [(AbsoluteAxisType::ABS_Z, abs(0, 1, 1)), (AbsoluteAxisType::ABS_RZ, abs(0, 1, 1))];
+        let (_, inverted) = source_ranges(&axes, None);
+        assert!(inverted.is_empty());
+    }
+
+    #[test]
+    fn configured_inversion_wins() {
+        let axes = [(AbsoluteAxisType::ABS_Z, abs(0, 255, 0))];
+        let device = config::DeviceConfig { invert_triggers: Some(true), ..Default::default() };
+        let (_, inverted) = source_ranges(&axes, Some(&device));
+        assert_eq!(inverted.get(&AbsoluteAxisType::ABS_Z.0), Some(&255));
+
+        let axes = [(AbsoluteAxisType::ABS_Z, abs(0, 255, 255)), (AbsoluteAxisType::ABS_RZ, abs(0, 255, 255))];
+        let device = config::DeviceConfig { invert_triggers: Some(false), ..Default::default() };
+        let (_, inverted) = source_ranges(&axes, Some(&device));
+        assert!(inverted.is_empty());
+    }
+
+    fn pressed(key: Key) -> HeldState {
+        let mut held = HeldState::default();
+        held.update(&InputEvent::new(EventType::KEY, key.0, 1));
+        held
+    }
+
+    #[test]
+    fn released_once_grace_runs_out() {
+        let (tx, rx) = mpsc::channel();
+        let shared = Shared::new(&Config::default());
+        wait_for_reconnect(&FrameSender::new(tx, 3, 0), &shared, "pad".to_string(), pressed(Key::BTN_SOUTH), Duration::ZERO);
+
+        let frame = rx.try_recv().unwrap();
+        assert_eq!(frame.source, 3);
+        assert_eq!(frame.events.len(), 1);
+        assert_eq!((frame.events[0].code(), frame.events[0].value()), (Key::BTN_SOUTH.0, 0));
+        assert!(shared.disconnected.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn nothing_released_when_back_in_time() {
+        let (tx, rx) = mpsc::channel();
+        let shared = Arc::new(Shared::new(&Config::default()));
+        let returning = shared.clone();
+        let reconnect = thread::spawn(move || loop {
+            if let Some((_, source)) = returning.disconnected.lock().unwrap().remove("pad") {
+                break source;
+            }
+            thread::sleep(Duration::from_millis(10));
+        });
+        wait_for_reconnect(&FrameSender::new(tx, 3, 0), &shared, "pad".to_string(), pressed(Key::BTN_SOUTH), Duration::from_secs(5));
+        // the device coming back gets to pick up its source again
+        assert_eq!(reconnect.join().unwrap(), 3);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn multitouch_axes() {
+        assert!(is_mt_axis(AbsoluteAxisType::ABS_MT_SLOT));
+        assert!(is_mt_axis(AbsoluteAxisType::ABS_MT_POSITION_X));
+        assert!(is_mt_axis(AbsoluteAxisType::ABS_MT_TRACKING_ID));
+        assert!(!is_mt_axis(AbsoluteAxisType::ABS_X));
+        assert!(!is_mt_axis(AbsoluteAxisType::ABS_HAT3Y));
+        assert!(!is_mt_axis(AbsoluteAxisType::ABS_MISC));
+    }
+
+    #[test]
+    fn grab_retries_back_off_during_boot() {
+        let window = Duration::from_secs(10);
+        assert_eq!(grab_retry_delay(0, Duration::ZERO, window), Some(Duration::from_millis(50)));
+        assert_eq!(grab_retry_delay(2, Duration::from_secs(1), window), Some(Duration::from_millis(200)));
+        // capped at 1.6s
+        assert_eq!(grab_retry_delay(5, Duration::from_secs(1), window), Some(Duration::from_millis(1600)));
+        assert_eq!(grab_retry_delay(40, Duration::from_secs(1), window), Some(Duration::from_millis(1600)));
+        assert_eq!(grab_retry_delay(0, window, window), None);
+        assert_eq!(grab_retry_delay(0, Duration::ZERO, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn autorepeat_is_only_held_keys() {
+        assert!(is_autorepeat(&InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 2)));
+        assert!(!is_autorepeat(&InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 1)));
+        assert!(!is_autorepeat(&InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 0)));
+        assert!(!is_autorepeat(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, 2)));
+    }
+
+    #[test]
+    fn hooks_get_the_device_name() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rinputer-hook-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (hook, out) = (dir.join("hook.sh"), dir.join("out"));
+        fs::write(&hook, format!("#!/bin/sh\necho \"$1\" > {}.tmp && mv {0}.tmp {0}\n", out.display())).unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+        run_hook(hook.to_str().unwrap(), "RG353 joypad");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !out.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let written = fs::read_to_string(&out);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written.unwrap(), "RG353 joypad\n");
+    }
+
+    #[test]
+    fn missing_hook_is_only_logged() {
+        run_hook("/nonexistent/rinputer-hook", "pad");
+    }
+
+    #[test]
+    fn steam_pads_by_id_not_name() {
+        let id = |vendor, product| InputId::new(BusType::BUS_USB, vendor, product, 1);
+        assert!(is_steam_id(id(0x28de, 0x11ff), ""));
+        assert!(is_steam_id(id(0x045e, 0x028e), ""));
+        // a real wired 360 pad has a physical path
+        assert!(!is_steam_id(id(0x045e, 0x028e), "usb-0000:00:14.0-2/input0"));
+        assert!(!is_steam_id(id(0x1209, 0x3100), ""));
+    }
+
+    #[test]
+    fn unknown_events_are_sampled() {
+        let logged: Vec<u64> = (0..10).filter(|seen| should_sample(*seen, 4)).collect();
+        assert_eq!(logged, [0, 4, 8]);
+        assert!((0..10).all(|seen| should_sample(seen, 1)));
+        assert!((0..10).all(|seen| !should_sample(seen, 0)));
+    }
+
+    #[test]
+    fn send_failing_means_shutdown() {
+        let (tx, rx) = mpsc::channel();
+        drop(rx);
+        let tx = FrameSender::new(tx, 3, 0);
+        let err: anyhow::Error = reset_to_neutral(&tx, &mut pressed(Key::BTN_SOUTH)).unwrap_err().into();
+        assert!(is_shutdown(&err));
+        assert!(!is_shutdown(&anyhow::anyhow!("No such device")));
+    }
+
+    #[test]
+    fn negative_triggers_use_their_whole_range() {
+        let axes = [(AbsoluteAxisType::ABS_Z, abs(-32768, 32767, -32768))];
+        let (ranges, _) = source_ranges(&axes, None);
+        assert_eq!(ranges[&AbsoluteAxisType::ABS_Z.0].minimum(), -32768);
+        assert_eq!(scale_onto(-32768, ranges.get(&AbsoluteAxisType::ABS_Z.0), AbsoluteAxisType::ABS_Z, &Config::default()), 0);
+
+        let device = config::DeviceConfig { bidirectional_triggers: Some(false), ..Default::default() };
+        let (ranges, _) = source_ranges(&axes, Some(&device));
+        let range = ranges.get(&AbsoluteAxisType::ABS_Z.0);
+        assert_eq!(range.unwrap().minimum(), 0);
+        assert_eq!(scale_onto(-20000, range, AbsoluteAxisType::ABS_Z, &Config::default()), 0);
+        assert_eq!(scale_onto(32767, range, AbsoluteAxisType::ABS_Z, &Config::default()), max_out_trig());
+    }
+
+    #[test]
+    fn own_nodes_are_never_grabbed() {
+        let config = Config::default();
+        let own: HashSet<PathBuf> = [PathBuf::from("/dev/input/event7"), PathBuf::from("/dev/input/js1")].into();
+        assert!(skip_node(Path::new("/dev/input/event7"), &own, &config));
+        assert!(skip_node(Path::new("/dev/input/js1"), &own, &config));
+        assert!(!skip_node(Path::new("/dev/input/event3"), &own, &config));
+    }
+
+    #[test]
+    fn excluded_nodes_are_skipped() {
+        let config = Config {
+            exclude: vec![PathBuf::from("/dev/input/event3")],
+            ..Config::default()
+        };
+        assert!(skip_node(Path::new("/dev/input/event3"), &HashSet::new(), &config));
+    }
+
+    #[test]
+    fn our_pad_is_recognized_by_id() {
+        assert!(is_rinputer(xbox_input_id()));
+        assert!(!is_rinputer(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 0x0114)));
+    }
+
+    #[test]
+    fn raw_relay_forwards_values_unchanged() {
+        let mut frame = PendingFrame::new(FRAME_DEADLINE);
+        let mut held = HeldState::default();
+        let now = Instant::now();
+        relay_raw(&mut frame, InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 1234), &mut held, now);
+        relay_raw(&mut frame, InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 1), &mut held, now);
+        let sent: Vec<(u16, i32)> = frame.take().iter().map(|ev| (ev.code(), ev.value())).collect();
+        assert_eq!(sent, vec![(AbsoluteAxisType::ABS_X.0, 1234), (Key::BTN_SOUTH.0, 1)]);
+        // only the key is released later on
+        assert_eq!(held.neutral_frame().len(), 1);
+    }
+
+    #[test]
+    fn autorepeat_never_reaches_the_pad() {
+        let events = || without_autorepeat([
+            InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 1),
+            InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 2),
+            InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 0),
+        ]);
+        let now = Instant::now();
+
+        // relayed as is by flat and passthrough
+        let mut frame = PendingFrame::new(FRAME_DEADLINE);
+        let mut held = HeldState::default();
+        for ev in events() {
+            relay_raw(&mut frame, ev, &mut held, now);
+        }
+        let sent: Vec<(u16, i32)> = frame.take().iter().map(|ev| (ev.code(), ev.value())).collect();
+        assert_eq!(sent, [(Key::BTN_EAST.0, 1), (Key::BTN_EAST.0, 0)]);
+
+        // or remapped to another button first
+        let config = Config::default();
+        let profile = config::DeviceProfile {
+            id: String::new(),
+            path: None,
+            keys: HashMap::from([("BTN_EAST".to_string(), "BTN_SOUTH".to_string())]),
+            axes: HashMap::new(),
+            source: PathBuf::new(),
+        };
+        let table = remap::RemapTable::from_profile(&profile);
+        let remap_fn: RemapFn = Box::new(move |ev, _| table.apply(ev));
+        let mut pipeline = pipeline::Pipeline::new(&config, None, &[], Some(remap_fn));
+        let sent: Vec<(u16, i32)> = events()
+            .flat_map(|ev| pipeline.process(ev, false, &config, now))
+            .map(|ev| (ev.code(), ev.value()))
+            .collect();
+        assert_eq!(sent, [(Key::BTN_SOUTH.0, 1), (Key::BTN_SOUTH.0, 0)]);
+
+        // and companion keys read the same way
+        let config = Config {
+            companion_keys: HashMap::from([("BTN_EAST".to_string(), "rb".to_string())]),
+            ..Config::default()
+        };
+        let keys = companion_keys(&config);
+        assert_eq!(events().filter_map(|ev| companion_event(&keys, &ev)).count(), 2);
+    }
+
+    #[test]
+    fn pads_switching_modes_are_new_devices() {
+        let dinput = InputId::new(BusType::BUS_USB, 0x2dc8, 0x6001, 0x0100);
+        let abs = [AbsoluteAxisType::ABS_X.0, AbsoluteAxisType::ABS_Y.0];
+        let before = identity(dinput, "8BitDo SN30 Pro", "e4:17:d8:00:00:01", &[Key::BTN_SOUTH.0, Key::BTN_EAST.0], &abs);
+        let returned = identity(dinput, "8BitDo SN30 Pro", "e4:17:d8:00:00:01", &[Key::BTN_SOUTH.0, Key::BTN_EAST.0], &abs);
+        assert_eq!(before, returned);
+
+        // same ids and name, but the buttons or axes changed
+        let more_keys = identity(dinput, "8BitDo SN30 Pro", "e4:17:d8:00:00:01", &[Key::BTN_SOUTH.0, Key::BTN_EAST.0, Key::BTN_MODE.0], &abs);
+        let fewer_axes = identity(dinput, "8BitDo SN30 Pro", "e4:17:d8:00:00:01", &[Key::BTN_SOUTH.0, Key::BTN_EAST.0], &abs[..1]);
+        assert_ne!(before, more_keys);
+        assert_ne!(before, fewer_axes);
+        assert_ne!(before, identity(dinput, "8BitDo SN30 Pro", "e4:17:d8:00:00:02", &[Key::BTN_SOUTH.0, Key::BTN_EAST.0], &abs));
+    }
+
+    #[test]
+    fn triggers_scale_onto_the_configured_resolution() {
+        let config = Config { trigger_max: 1023, ..Config::default() };
+        assert_eq!(output_range(AbsoluteAxisType::ABS_RZ, &config), (MIN_OUT_TRIG, 1023));
+        assert_eq!(output_range(AbsoluteAxisType::ABS_X, &config), config.analog_range());
+
+        let trigger = AbsInfo::new(0, 0, 255, 0, 0, 0);
+        assert_eq!(scale_onto(255, Some(&trigger), AbsoluteAxisType::ABS_Z, &config), 1023);
+        assert_eq!(scale_onto(128, Some(&trigger), AbsoluteAxisType::ABS_Z, &config), 514);
+        assert_eq!(scale_onto(0, Some(&trigger), AbsoluteAxisType::ABS_Z, &config), 0);
+        assert_eq!(output_range(AbsoluteAxisType::ABS_Z, &Config { trigger_max: 0, ..Config::default() }), (MIN_OUT_TRIG, 1));
+    }
+
+    #[test]
+    fn companions_share_a_device_or_are_built_in() {
+        let usb_pad = [(Some("usb-0000:00:14.0-2/input0".to_string()), BusType::BUS_USB)];
+        assert!(companion_of(Some("usb-0000:00:14.0-2/input1"), BusType::BUS_USB, &usb_pad));
+        assert!(!companion_of(Some("usb-0000:00:14.0-3/input1"), BusType::BUS_USB, &usb_pad));
+        assert!(!companion_of(None, BusType::BUS_USB, &[(None, BusType::BUS_USB)]));
+
+        // a handheld's gpio-keys node next to its adc joypad, their phys have nothing in common
+        let built_in_pad = [(Some("retrogame_joypad/input0".to_string()), BusType::BUS_HOST)];
+        assert!(companion_of(Some("gpio-keys/input0"), BusType::BUS_HOST, &built_in_pad));
+        assert!(companion_of(None, BusType::BUS_HOST, &built_in_pad));
+        // but a laptop's hotkeys don't belong to a usb pad
+        assert!(!companion_of(Some("asus-nb-wmi/input0"), BusType::BUS_HOST, &usb_pad));
+        assert!(!companion_of(Some("gpio-keys/input0"), BusType::BUS_HOST, &[]));
+    }
+
+    #[test]
+    fn volume_up_goes_out_as_the_configured_button() {
+        let config = Config {
+            companion_keys: HashMap::from([("KEY_VOLUMEUP".to_string(), "rb".to_string()), ("KEY_NOPE".to_string(), "tl".to_string())]),
+            ..Config::default()
+        };
+        let keys = companion_keys(&config);
+        assert_eq!(keys.len(), 1);
+
+        let pressed = companion_event(&keys, &InputEvent::new(EventType::KEY, Key::KEY_VOLUMEUP.0, 1)).unwrap();
+        assert_eq!((pressed.event_type(), pressed.code(), pressed.value()), (EventType::KEY, Key::BTN_TR.0, 1));
+        assert!(companion_event(&keys, &InputEvent::new(EventType::KEY, Key::KEY_VOLUMEDOWN.0, 1)).is_none());
+    }
+
+    #[test]
+    fn neutral_frame_releases_exactly_whats_held() {
+        let (tx, rx) = mpsc::channel();
+        let tx = FrameSender::new(tx, 3, 0);
+        let mut held = pressed(Key::BTN_SOUTH);
+        held.update(&InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 1));
+        held.update(&InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 0));
+        held.update(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, -20000));
+        reset_to_neutral(&tx, &mut held).unwrap();
+
+        let mut released: Vec<(u16, u16, i32)> = rx.try_recv().unwrap().events.iter()
+            .map(|ev| (ev.event_type().0, ev.code(), ev.value()))
+            .collect();
+        released.sort();
+        assert_eq!(released, [(EventType::KEY.0, Key::BTN_SOUTH.0, 0), (EventType::ABSOLUTE.0, AbsoluteAxisType::ABS_X.0, 0)]);
+
+        // nothing left to release the second time around
+        reset_to_neutral(&tx, &mut held).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn sticks_scale_onto_the_full_range() {
+        for (min, max) in [(0, 255), (-128, 127), (-32768, 32767), (-32767, 32767), (0, 4095)] {
+            let src = abs(min, max, 0);
+            assert_eq!(scale_axis(min, &src, MIN_OUT_ANALOG, MAX_OUT_ANALOG), MIN_OUT_ANALOG, "{}..{}", min, max);
+            assert_eq!(scale_axis(max, &src, MIN_OUT_ANALOG, MAX_OUT_ANALOG), MAX_OUT_ANALOG, "{}..{}", min, max);
+            assert_eq!(scale_axis(min, &src, -MAX_OUT_ANALOG, MAX_OUT_ANALOG), -MAX_OUT_ANALOG, "{}..{}", min, max);
+            // out of range values don't make it out
+            assert_eq!(scale_axis(max + 100, &src, MIN_OUT_ANALOG, MAX_OUT_ANALOG), MAX_OUT_ANALOG, "{}..{}", min, max);
+            assert_eq!(scale_axis(min - 100, &src, MIN_OUT_ANALOG, MAX_OUT_ANALOG), MIN_OUT_ANALOG, "{}..{}", min, max);
+        }
+        // at rest, or as close to it as the range gets
+        assert_eq!(scale_axis(0, &abs(-32767, 32767, 0), MIN_OUT_ANALOG, MAX_OUT_ANALOG), 0);
+        assert_eq!(scale_axis(0, &abs(-32768, 32767, 0), MIN_OUT_ANALOG, MAX_OUT_ANALOG), 0);
+        assert_eq!(scale_axis(2048, &abs(-2048, 6144, 0), MIN_OUT_ANALOG, MAX_OUT_ANALOG), 0);
+        assert!(scale_axis(127, &abs(0, 255, 0), MIN_OUT_ANALOG, MAX_OUT_ANALOG).abs() <= 129);
+        assert!(scale_axis(0, &abs(-128, 127, 0), MIN_OUT_ANALOG, MAX_OUT_ANALOG).abs() <= 129);
+        // halfway either side
+        assert_eq!(scale_axis(16384, &abs(-32767, 32767, 0), -MAX_OUT_ANALOG, MAX_OUT_ANALOG), 16384);
+        assert_eq!(scale_axis(-16384, &abs(-32767, 32767, 0), -MAX_OUT_ANALOG, MAX_OUT_ANALOG), -16384);
+    }
+
+    #[test]
+    fn triggers_scale_end_to_end() {
+        assert_eq!(scale_axis(0, &abs(0, 1023, 0), MIN_OUT_TRIG, 255), 0);
+        assert_eq!(scale_axis(512, &abs(0, 1023, 0), MIN_OUT_TRIG, 255), 128);
+        assert_eq!(scale_axis(1023, &abs(0, 1023, 0), MIN_OUT_TRIG, 255), 255);
+        assert_eq!(scale_axis(-32768, &abs(-32768, 32767, 0), MIN_OUT_TRIG, 255), 0);
+        assert_eq!(scale_axis(5, &abs(3, 3, 0), MIN_OUT_TRIG, 255), 5);
+    }
+
+    #[test]
+    fn hats_of_any_range_come_out_as_three_states() {
+        for (min, max) in [(-1, 1), (-32767, 32767), (0, 255), (-8, 8)] {
+            let src = abs(min, max, 0);
+            assert_eq!(scale_hat(min, Some(&src)), MIN_OUT_HAT, "{}..{}", min, max);
+            assert_eq!(scale_hat(max, Some(&src)), MAX_OUT_HAT, "{}..{}", min, max);
+            assert_eq!(scale_hat((min + max) / 2, Some(&src)), 0, "{}..{}", min, max);
+        }
+        // a quarter of the range either side of center is still centered
+        assert_eq!(scale_hat(8000, Some(&abs(-32767, 32767, 0))), 0);
+        assert_eq!(scale_hat(20000, Some(&abs(-32767, 32767, 0))), MAX_OUT_HAT);
+        assert_eq!(scale_hat(-20000, Some(&abs(-32767, 32767, 0))), MIN_OUT_HAT);
+        // nothing sensible declared, whatever comes in is clamped
+        assert_eq!(scale_hat(-32767, None), MIN_OUT_HAT);
+        assert_eq!(scale_hat(5, Some(&abs(0, 0, 0))), MAX_OUT_HAT);
+        assert_eq!(scale_hat(0, None), 0);
+    }
+
+    #[test]
+    fn a_pad_for_every_claimed_slot() {
+        assert_eq!(pad_changes(&[0], &[]), (vec![], vec![]));
+        assert_eq!(pad_changes(&[0], &[0, 1]), (vec![1], vec![]));
+        assert_eq!(pad_changes(&[0, 1, 2], &[2]), (vec![], vec![1]));
+        assert_eq!(pad_changes(&[0, 2], &[1, 2, 3]), (vec![1, 3], vec![]));
+    }
+}
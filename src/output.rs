@@ -0,0 +1,297 @@
+use evdev::{
+    AbsoluteAxisType,
+    InputEventKind,
+    InputEvent,
+    EventType,
+};
+use crate::{
+    config::HatPolicy,
+    state::Frame,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+// folds both triggers into ABS_Z for old games, the left one pulling negative and the right one positive
+#[derive(Default)]
+pub struct TriggerCombiner {
+    left: i32,
+    right: i32,
+}
+
+impl TriggerCombiner {
+    pub fn apply(&mut self, ev: InputEvent) -> InputEvent {
+        match ev.kind() {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Z) => self.left = ev.value(),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RZ) => self.right = ev.value(),
+            _ => return ev,
+        }
+        InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, self.right - self.left)
+    }
+}
+
+const STICKS: [AbsoluteAxisType; 4] = [
+    AbsoluteAxisType::ABS_X,
+    AbsoluteAxisType::ABS_Y,
+    AbsoluteAxisType::ABS_RX,
+    AbsoluteAxisType::ABS_RY,
+];
+
+// re-centers sticks left slightly off center once they've been still for a while,
+// so nothing downstream keeps drifting on a stale value
+pub struct IdleCenter {
+    timeout: Duration,
+    near: i32,
+    last_motion: Instant,
+    sticks: HashMap<u16, i32>,
+    fired: bool,
+}
+
+impl IdleCenter {
+    pub fn new(timeout: Duration, near: i32) -> IdleCenter {
+        IdleCenter {
+            timeout,
+            near,
+            last_motion: Instant::now(),
+            sticks: HashMap::new(),
+            fired: true,
+        }
+    }
+
+    pub fn observe(&mut self, ev: &InputEvent, now: Instant) {
+        if let InputEventKind::AbsAxis(axis) = ev.kind() {
+            if STICKS.contains(&axis) {
+                self.sticks.insert(axis.0, ev.value());
+                self.last_motion = now;
+                self.fired = false;
+            }
+        }
+    }
+
+    // the centered frame, once per idle period and only if no stick is actually deflected
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<InputEvent>> {
+        if self.fired || now.duration_since(self.last_motion) < self.timeout {
+            return None;
+        }
+        self.fired = true;
+
+        if self.sticks.values().any(|value| value.abs() > self.near) {
+            return None;
+        }
+        self.sticks.values_mut().for_each(|value| *value = 0);
+        Some(STICKS.iter()
+            .map(|axis| InputEvent::new(EventType::ABSOLUTE, axis.0, 0))
+            .collect())
+    }
+
+    // how long until poll may have something to say, nothing until the sticks move again once it did
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        match self.fired {
+            true => None,
+            false => Some((self.last_motion + self.timeout).saturating_duration_since(now)),
+        }
+    }
+}
+
+// holds axis updates back and sends only the latest value of each once per window,
+// which puts a hard bound on how often we emit
+pub struct Coalescer {
+    window: Duration,
+    pending: BTreeMap<u16, i32>,
+    next_tick: Instant,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Coalescer {
+        Coalescer {
+            window,
+            pending: BTreeMap::new(),
+            next_tick: Instant::now(),
+        }
+    }
+
+    // whatever has to go out right away, which is anything but axes
+    pub fn push(&mut self, ev: InputEvent) -> Option<InputEvent> {
+        let InputEventKind::AbsAxis(axis) = ev.kind() else { return Some(ev) };
+        self.pending.insert(axis.0, ev.value());
+        None
+    }
+
+    pub fn flush(&mut self, now: Instant) -> Vec<InputEvent> {
+        if self.pending.is_empty() || now < self.next_tick {
+            return Vec::new();
+        }
+        self.next_tick = now + self.window;
+        std::mem::take(&mut self.pending).into_iter()
+            .map(|(axis, value)| InputEvent::new(EventType::ABSOLUTE, axis, value))
+            .collect()
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        match self.pending.is_empty() {
+            true => None,
+            false => Some(self.next_tick.saturating_duration_since(now)),
+        }
+    }
+}
+
+// a direction some device holds on a hat axis
+struct Pressed {
+    value: i32,
+    priority: usize,
+    since: Instant,
+}
+
+// merges the dpads of all devices according to the configured policy
+pub struct HatMixer {
+    policy: HatPolicy,
+    // by hat axis, whatever every device currently holds there
+    sources: HashMap<u16, HashMap<u64, Pressed>>,
+    resolved: HashMap<u16, i32>,
+}
+
+impl HatMixer {
+    pub fn new(policy: HatPolicy) -> HatMixer {
+        HatMixer {
+            policy,
+            sources: HashMap::new(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    // the frame's events with hats replaced by the merged value, dropped if that didn't change
+    pub fn apply(&mut self, frame: Frame, now: Instant) -> Vec<InputEvent> {
+        let mut out = Vec::with_capacity(frame.events.len());
+        for ev in frame.events {
+            let axis = match ev.kind() {
+                InputEventKind::AbsAxis(axis @ (AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y)) => axis,
+                _ => {
+                    out.push(ev);
+                    continue;
+                },
+            };
+
+            let sources = self.sources.entry(axis.0).or_default();
+            if ev.value() == 0 {
+                sources.remove(&frame.source);
+            } else {
+                sources.insert(frame.source, Pressed { value: ev.value(), priority: frame.priority, since: now });
+            }
+
+            let value = resolve(self.policy, sources.values());
+            if self.resolved.insert(axis.0, value) != Some(value) {
+                out.push(InputEvent::new(EventType::ABSOLUTE, axis.0, value));
+            }
+        }
+        out
+    }
+}
+
+fn resolve<'a>(policy: HatPolicy, pressed: impl Iterator<Item = &'a Pressed>) -> i32 {
+    let mut pressed: Vec<&Pressed> = pressed.collect();
+    match policy {
+        HatPolicy::Last => pressed.iter().max_by_key(|p| p.since).map_or(0, |p| p.value),
+        HatPolicy::Priority => {
+            // the most recent one breaks ties between equally ranked devices
+            pressed.sort_by_key(|p| (p.priority, std::cmp::Reverse(p.since)));
+            pressed.first().map_or(0, |p| p.value)
+        },
+        HatPolicy::Neutral => {
+            let positive = pressed.iter().any(|p| p.value > 0);
+            let negative = pressed.iter().any(|p| p.value < 0);
+            match (positive, negative) {
+                (true, false) => 1,
+                (false, true) => -1,
+                _ => 0,
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    fn value(ev: &InputEvent) -> (u16, i32) {
+        (ev.code(), ev.value())
+    }
+
+    #[test]
+    fn triggers_combine_onto_z() {
+        let mut combiner = TriggerCombiner::default();
+        assert_eq!(value(&combiner.apply(abs(AbsoluteAxisType::ABS_Z, 200))), (AbsoluteAxisType::ABS_Z.0, -200));
+        assert_eq!(value(&combiner.apply(abs(AbsoluteAxisType::ABS_RZ, 255))), (AbsoluteAxisType::ABS_Z.0, 55));
+        assert_eq!(value(&combiner.apply(abs(AbsoluteAxisType::ABS_Z, 0))), (AbsoluteAxisType::ABS_Z.0, 255));
+        assert_eq!(value(&combiner.apply(abs(AbsoluteAxisType::ABS_X, 7))), (AbsoluteAxisType::ABS_X.0, 7));
+    }
+
+    #[test]
+    fn idle_sticks_get_centered_once() {
+        let start = Instant::now();
+        let mut idle = IdleCenter::new(Duration::from_secs(5), 1000);
+        assert_eq!(idle.due_in(start), None);
+
+        idle.observe(&abs(AbsoluteAxisType::ABS_X, 300), start);
+        assert_eq!(idle.due_in(start + Duration::from_secs(1)), Some(Duration::from_secs(4)));
+        assert!(idle.poll(start + Duration::from_secs(1)).is_none());
+
+        let frame = idle.poll(start + Duration::from_secs(5)).unwrap();
+        assert!(frame.iter().all(|ev| ev.value() == 0) && frame.len() == STICKS.len());
+        assert_eq!(idle.due_in(start + Duration::from_secs(6)), None);
+        assert!(idle.poll(start + Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn deflected_sticks_stay_put() {
+        let start = Instant::now();
+        let mut idle = IdleCenter::new(Duration::from_secs(5), 1000);
+        idle.observe(&abs(AbsoluteAxisType::ABS_RY, -20000), start);
+        assert!(idle.poll(start + Duration::from_secs(5)).is_none());
+        assert_eq!(idle.due_in(start + Duration::from_secs(5)), None);
+    }
+
+    #[test]
+    fn axis_updates_collapse_to_one_per_window() {
+        let mut coalescer = Coalescer::new(Duration::from_millis(10));
+        let start = coalescer.next_tick;
+        assert_eq!(coalescer.due_in(start), None);
+
+        for x in [100, 200, 300] {
+            assert!(coalescer.push(abs(AbsoluteAxisType::ABS_X, x)).is_none());
+        }
+        coalescer.push(abs(AbsoluteAxisType::ABS_Y, -5));
+        // anything that isn't an axis isn't held back
+        assert!(coalescer.push(InputEvent::new(EventType::KEY, 0x130, 1)).is_some());
+        let out: Vec<(u16, i32)> = coalescer.flush(start).iter().map(value).collect();
+        assert_eq!(out, [(AbsoluteAxisType::ABS_X.0, 300), (AbsoluteAxisType::ABS_Y.0, -5)]);
+
+        // the next window
+        coalescer.push(abs(AbsoluteAxisType::ABS_X, 400));
+        coalescer.push(abs(AbsoluteAxisType::ABS_X, 500));
+        assert_eq!(coalescer.due_in(start), Some(Duration::from_millis(10)));
+        assert!(coalescer.flush(start + Duration::from_millis(5)).is_empty());
+        let out: Vec<(u16, i32)> = coalescer.flush(start + Duration::from_millis(10)).iter().map(value).collect();
+        assert_eq!(out, [(AbsoluteAxisType::ABS_X.0, 500)]);
+        assert!(coalescer.flush(start + Duration::from_millis(30)).is_empty());
+    }
+
+    fn hats(source: u64, value: i32) -> Frame {
+        Frame { source, priority: 0, pad: 0, events: vec![abs(AbsoluteAxisType::ABS_HAT0X, value)] }
+    }
+
+    #[test]
+    fn hats_are_released_by_whoever_held_them() {
+        let mut mixer = HatMixer::new(HatPolicy::Last);
+        let now = Instant::now();
+        assert_eq!(mixer.apply(hats(1, -1), now).iter().map(value).collect::<Vec<_>>(), [(AbsoluteAxisType::ABS_HAT0X.0, -1)]);
+        // another device letting go of a direction it never held changes nothing
+        assert!(mixer.apply(hats(2, 0), now).is_empty());
+        // the first one came back on another node, same source though
+        assert_eq!(mixer.apply(hats(1, 0), now).iter().map(value).collect::<Vec<_>>(), [(AbsoluteAxisType::ABS_HAT0X.0, 0)]);
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{Context, Result};
+use evdev::{
+    EventType,
+    InputEvent,
+    uinput::VirtualDevice,
+};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use libdogd::log_debug;
+use crate::{
+    chord::{Combo, Sequence},
+    config::{Config, Profile},
+    output,
+    rumble,
+    state::{Frame, HeldState},
+    Shared,
+};
+
+// everything between the handlers and a virtual pad, merging what the devices behind it send
+struct Stages {
+    trigger_combiner: output::TriggerCombiner,
+    idle_center: Option<output::IdleCenter>,
+    combos: Vec<Combo>,
+    sequences: Vec<Sequence>,
+    hat_mixer: output::HatMixer,
+    coalescer: Option<output::Coalescer>,
+}
+
+impl Stages {
+    fn new(config: &Config) -> Stages {
+        Stages {
+            trigger_combiner: output::TriggerCombiner::default(),
+            // anything within 5% of center counts as idling
+            idle_center: match config.idle_center_secs {
+                0 => None,
+                secs => Some(output::IdleCenter::new(Duration::from_secs(secs), crate::MAX_OUT_ANALOG / 20)),
+            },
+            combos: crate::combos(config),
+            sequences: crate::sequences(config),
+            hat_mixer: output::HatMixer::new(config.hat_conflict),
+            coalescer: match config.coalesce_ms {
+                0 => None,
+                ms => Some(output::Coalescer::new(Duration::from_millis(ms))),
+            },
+        }
+    }
+
+    fn due_in(&self, now: Instant) -> Option<Duration> {
+        [
+            self.idle_center.as_ref().and_then(|idle| idle.due_in(now)),
+            self.combos.iter().filter_map(|combo| combo.due_in(now)).min(),
+            self.sequences.iter().filter_map(|seq| seq.due_in(now)).min(),
+            self.coalescer.as_ref().and_then(|coalescer| coalescer.due_in(now)),
+        ].into_iter().flatten().min()
+    }
+
+    // what goes out for a frame from one of the devices, or for nothing if only timers are due
+    fn process(&mut self, frame: Option<Frame>, profile: Profile, config: &Config, raw_axes: &HashSet<u16>, now: Instant) -> Vec<InputEvent> {
+        // flat mode and the passthrough profile relay frames as they came in, none of the merging applies
+        if config.flat || profile == Profile::Passthrough {
+            return frame.map(|frame| frame.events).unwrap_or_default();
+        }
+
+        // combos and sequences see everything from every device of the pad, and may hold some of it back for a while
+        let mut batch = frame.map(|frame| self.hat_mixer.apply(frame, now)).unwrap_or_default();
+        for combo in self.combos.iter_mut() {
+            let mut next = combo.poll(now);
+            for ev in batch {
+                next.append(&mut combo.feed(ev, now));
+            }
+            batch = next;
+        }
+        for seq in self.sequences.iter_mut() {
+            let mut next = seq.poll(now);
+            for ev in batch {
+                next.append(&mut seq.feed(ev, now));
+            }
+            batch = next;
+        }
+
+        let mut out = Vec::with_capacity(batch.len());
+        for ev in batch {
+            let ev = if config.combined_triggers && profile == Profile::Xbox {
+                self.trigger_combiner.apply(ev)
+            } else {
+                ev
+            };
+            if let Some(idle) = self.idle_center.as_mut() {
+                idle.observe(&ev, now);
+            }
+            match self.coalescer.as_mut() {
+                Some(coalescer) if !raw_axes.contains(&ev.code()) || ev.event_type() != EventType::ABSOLUTE => {
+                    out.extend(coalescer.push(ev));
+                },
+                _ => out.push(ev),
+            }
+        }
+        if let Some(coalescer) = self.coalescer.as_mut() {
+            out.append(&mut coalescer.flush(now));
+        }
+        out
+    }
+
+    // passthrough axes may not be centered at 0
+    fn idle(&mut self, profile: Profile, config: &Config, now: Instant) -> Option<Vec<InputEvent>> {
+        match (self.idle_center.as_mut(), profile) {
+            (Some(idle), Profile::Xbox) if !config.flat => idle.poll(now),
+            _ => None,
+        }
+    }
+}
+
+// a device we never learned the syspath of counts as gone too
+fn any_missing(syspath: Option<&Path>, nodes: &[PathBuf]) -> bool {
+    syspath.is_none_or(|syspath| !syspath.exists()) || nodes.iter().any(|node| !node.exists())
+}
+
+// only the xbox pad declares FF_RUMBLE
+fn listen(uhandle: &Arc<Mutex<VirtualDevice>>, index: usize, profile: Profile, config: &Config, shared: &Shared) -> Option<rumble::Listener> {
+    (config.rumble && profile == Profile::Xbox).then(|| rumble::Listener::start(uhandle, shared.rumblers.clone(), index))
+}
+
+// one virtual pad, along with everything between the handlers and what it emits
+pub struct Pad {
+    index: usize,
+    // declared first so it's stopped before the device it listens on goes away
+    rumble: Option<rumble::Listener>,
+    uhandle: Arc<Mutex<VirtualDevice>>,
+    syspath: Option<PathBuf>,
+    nodes: Vec<PathBuf>,
+    // what the virtual device currently has pressed or deflected, across all sources
+    emitted: HeldState,
+    stages: Stages,
+}
+
+impl Pad {
+    pub fn new(index: usize, profile: Profile, config: &Config, shared: &Shared) -> Result<Pad> {
+        let mut uhandle = crate::build_device(profile, config)?;
+        let nodes = crate::register_own_nodes(&mut uhandle, shared)
+            .context("Failed to enumerate virtual device nodes")?;
+        let syspath = uhandle.get_syspath().ok();
+        let uhandle = Arc::new(Mutex::new(uhandle));
+
+        Ok(Pad {
+            index,
+            rumble: listen(&uhandle, index, profile, config, shared),
+            uhandle,
+            syspath,
+            nodes,
+            emitted: HeldState::default(),
+            stages: Stages::new(config),
+        })
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn syspath(&self) -> Option<&Path> {
+        self.syspath.as_deref()
+    }
+
+    // something outside our control, a udev rule or whatever, can remove the virtual device under us
+    pub fn is_missing(&self) -> bool {
+        any_missing(self.syspath.as_deref(), &self.nodes)
+    }
+
+    // for a player that's gone, the device goes away along with it
+    pub fn retire(self, shared: &Shared) {
+        shared.own_nodes.lock().unwrap().retain(|node| !self.nodes.contains(node));
+        shared.rumblers.clear(self.index);
+    }
+
+    // the new device starts out neutral, so whatever is held down right now gets sent again
+    pub fn rebuild(&mut self, profile: Profile, config: &Config, shared: &Shared) -> Result<()> {
+        shared.own_nodes.lock().unwrap().retain(|node| !self.nodes.contains(node));
+        self.rumble = None;
+        shared.rumblers.clear(self.index);
+        let mut uhandle = crate::build_device(profile, config)?;
+        self.nodes = crate::register_own_nodes(&mut uhandle, shared)?;
+        self.syspath = uhandle.get_syspath().ok();
+        self.uhandle = Arc::new(Mutex::new(uhandle));
+        self.rumble = listen(&self.uhandle, self.index, profile, config, shared);
+
+        let replay = self.emitted.current_frame();
+        if !replay.is_empty() {
+            self.uhandle.lock().unwrap().emit(&replay)?;
+        }
+        Ok(())
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        self.stages.due_in(now)
+    }
+
+    // a frame from one of its devices, or nothing if only timers are due
+    pub fn feed(&mut self, frame: Option<Frame>, profile: Profile, config: &Config, raw_axes: &HashSet<u16>) -> Result<()> {
+        let out = self.stages.process(frame, profile, config, raw_axes, Instant::now());
+        // one SYN_REPORT per source frame
+        if !out.is_empty() {
+            out.iter().for_each(|ev| self.emitted.update(ev));
+            self.uhandle.lock().unwrap().emit(&out)?;
+        }
+
+        if let Some(frame) = self.stages.idle(profile, config, Instant::now()) {
+            log_debug("Sticks idle, sending a centered frame");
+            frame.iter().for_each(|ev| self.emitted.update(ev));
+            self.uhandle.lock().unwrap().emit(&frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{AbsoluteAxisType, Key};
+    use crate::config::SequenceConfig;
+
+    fn frame(source: u64, events: Vec<InputEvent>) -> Option<Frame> {
+        Some(Frame { source, priority: 0, pad: 0, events })
+    }
+
+    fn values(events: &[InputEvent]) -> Vec<(u16, i32)> {
+        events.iter().map(|ev| (ev.code(), ev.value())).collect()
+    }
+
+    fn sequence_config(flat: bool) -> Config {
+        Config {
+            flat,
+            sequences: vec![SequenceConfig { keys: vec!["select".into(), "start".into()], output: "guide".into(), window_ms: 50, swallow: true }],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn sequences_hold_keys_back() {
+        let config = sequence_config(false);
+        let mut stages = Stages::new(&config);
+        let select = InputEvent::new(EventType::KEY, Key::BTN_SELECT.0, 1);
+        assert!(stages.process(frame(1, vec![select]), Profile::Xbox, &config, &HashSet::new(), Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn flat_mode_relays_verbatim() {
+        let config = sequence_config(true);
+        let mut stages = Stages::new(&config);
+        let now = Instant::now();
+        let select = InputEvent::new(EventType::KEY, Key::BTN_SELECT.0, 1);
+        let out = stages.process(frame(1, vec![select]), Profile::Xbox, &config, &HashSet::new(), now);
+        assert_eq!(values(&out), [(Key::BTN_SELECT.0, 1)]);
+
+        // no hat merging either, raw values and all
+        let hat = |value| InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, value);
+        stages.process(frame(1, vec![hat(-1)]), Profile::Xbox, &config, &HashSet::new(), now);
+        let out = stages.process(frame(2, vec![hat(0)]), Profile::Xbox, &config, &HashSet::new(), now);
+        assert_eq!(values(&out), [(AbsoluteAxisType::ABS_HAT0X.0, 0)]);
+    }
+
+    #[test]
+    fn passthrough_relays_verbatim() {
+        let config = sequence_config(false);
+        let mut stages = Stages::new(&config);
+        let ev = InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 1000);
+        let select = InputEvent::new(EventType::KEY, Key::BTN_SELECT.0, 1);
+        let out = stages.process(frame(1, vec![ev, select]), Profile::Passthrough, &config, &HashSet::new(), Instant::now());
+        assert_eq!(values(&out), [(AbsoluteAxisType::ABS_X.0, 1000), (Key::BTN_SELECT.0, 1)]);
+    }
+
+    #[test]
+    fn missing_nodes_are_noticed() {
+        let dir = std::env::temp_dir().join(format!("rinputer-pad-{}", std::process::id()));
+        let (event, js) = (dir.join("event7"), dir.join("js0"));
+        std::fs::create_dir_all(&event).unwrap();
+        std::fs::create_dir_all(&js).unwrap();
+        let nodes = vec![event.clone(), js.clone()];
+        let all_there = any_missing(Some(&dir), &nodes);
+        let no_syspath = any_missing(None, &nodes);
+        std::fs::remove_dir(&js).unwrap();
+        let node_gone = any_missing(Some(&dir), &nodes);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let syspath_gone = any_missing(Some(&dir), &[]);
+
+        assert!(!all_there);
+        assert!(no_syspath);
+        assert!(node_gone);
+        assert!(syspath_gone);
+    }
+
+    #[test]
+    fn raw_axes_skip_the_coalescer() {
+        let config = Config { coalesce_ms: 100, ..Config::default() };
+        let mut stages = Stages::new(&config);
+        let raw_axes = HashSet::from([AbsoluteAxisType::ABS_Z.0]);
+        let now = Instant::now();
+        let abs = |axis: AbsoluteAxisType, value| InputEvent::new(EventType::ABSOLUTE, axis.0, value);
+        // the first window is open right away
+        stages.process(frame(1, vec![abs(AbsoluteAxisType::ABS_X, 1)]), Profile::Xbox, &config, &raw_axes, now);
+
+        let out = stages.process(frame(1, vec![abs(AbsoluteAxisType::ABS_X, 1000), abs(AbsoluteAxisType::ABS_Z, 200)]), Profile::Xbox, &config, &raw_axes, now);
+        assert_eq!(values(&out), [(AbsoluteAxisType::ABS_Z.0, 200)]);
+        let out = stages.process(None, Profile::Xbox, &config, &raw_axes, now + Duration::from_millis(100));
+        assert_eq!(values(&out), [(AbsoluteAxisType::ABS_X.0, 1000)]);
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    UinputAbsSetup,
+    AbsoluteAxisType,
+    AttributeSet,
+    AbsInfo,
+    BusType,
+    InputId,
+    Device,
+    Key,
+};
+use std::collections::BTreeMap;
+
+// union of everything the relayed devices can report
+pub struct Capabilities {
+    input_id: Option<InputId>,
+    keys: AttributeSet<Key>,
+    axes: BTreeMap<u16, AbsInfo>,
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        Capabilities {
+            input_id: None,
+            keys: AttributeSet::new(),
+            axes: BTreeMap::new(),
+        }
+    }
+
+    pub fn merge(&mut self, dev: &Device) {
+        let keys: Vec<Key> = dev.supported_keys().map(|keys| keys.iter().collect()).unwrap_or_default();
+        let axes: Vec<(u16, AbsInfo)> = match (dev.supported_absolute_axes(), dev.get_abs_state()) {
+            (Some(axes), Ok(absinfo)) => axes.iter()
+                .map(|axis| {
+                    let info = absinfo[axis.0 as usize];
+                    (axis.0, AbsInfo::new(0, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.add(dev.input_id(), keys, axes);
+    }
+
+    // axes present on multiple devices get the widest range of them all
+    fn add(&mut self, id: InputId, keys: Vec<Key>, axes: Vec<(u16, AbsInfo)>) {
+        self.input_id.get_or_insert(id);
+
+        for key in keys {
+            self.keys.insert(key);
+        }
+
+        for (code, info) in axes {
+            self.axes.entry(code)
+                .and_modify(|known| *known = AbsInfo::new(0,
+                    known.minimum().min(info.minimum()),
+                    known.maximum().max(info.maximum()),
+                    known.fuzz(), known.flat(), known.resolution()))
+                .or_insert(info);
+        }
+    }
+}
+
+pub fn build_device(caps: &Capabilities) -> Result<VirtualDevice> {
+    // mirror the first device's ids so that SDL & co. pick the right mapping for it
+    let source_id = caps.input_id.unwrap_or_else(|| InputId::new(BusType::BUS_VIRTUAL, 0, 0, 0));
+    let input_id = InputId::new(source_id.bus_type(), source_id.vendor(), source_id.product(), crate::RINPUTER_VERSION);
+
+    let mut builder = VirtualDeviceBuilder::new()
+        .context("Failed to create instance of evdev::VirtualDeviceBuilder")?
+        .name(b"rinputer4_5 passthrough")
+        .input_id(input_id)
+        .with_keys(&caps.keys)?;
+
+    for (code, info) in &caps.axes {
+        builder = builder.with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType(*code), *info))?;
+    }
+
+    builder.build().context("Failed to create passthrough uinput device")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widest_range_wins() {
+        let mut caps = Capabilities::new();
+        caps.add(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 1), vec![Key::BTN_SOUTH],
+            vec![(AbsoluteAxisType::ABS_X.0, AbsInfo::new(0, -128, 127, 4, 8, 0))]);
+        caps.add(InputId::new(BusType::BUS_HOST, 0x1209, 0x3100, 1), vec![Key::BTN_EAST],
+            vec![(AbsoluteAxisType::ABS_X.0, AbsInfo::new(0, 0, 1023, 0, 0, 0)),
+                 (AbsoluteAxisType::ABS_Z.0, AbsInfo::new(0, 0, 255, 0, 0, 0))]);
+
+        let x = caps.axes[&AbsoluteAxisType::ABS_X.0];
+        assert_eq!((x.minimum(), x.maximum(), x.fuzz()), (-128, 1023, 4));
+        assert_eq!(caps.axes[&AbsoluteAxisType::ABS_Z.0].maximum(), 255);
+        assert!(caps.keys.contains(Key::BTN_SOUTH) && caps.keys.contains(Key::BTN_EAST));
+    }
+
+    #[test]
+    fn first_device_names_the_pad() {
+        let mut caps = Capabilities::new();
+        caps.add(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 1), Vec::new(), Vec::new());
+        caps.add(InputId::new(BusType::BUS_HOST, 0x1209, 0x3100, 1), Vec::new(), Vec::new());
+        let id = caps.input_id.unwrap();
+        assert_eq!((id.vendor(), id.product()), (0x045e, 0x028e));
+    }
+}
@@ -0,0 +1,322 @@
+use evdev::{
+    AbsoluteAxisType,
+    InputEventKind,
+    InputEvent,
+    EventType,
+    AbsInfo,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use libdogd::log_info;
+use crate::{
+    config::{Config, DeviceConfig},
+    remap,
+    transform,
+    RemapFn,
+};
+
+// everything one device's events go through on their way to main, in order
+pub struct Pipeline {
+    src_ranges: HashMap<u16, AbsInfo>,
+    inverted_triggers: HashMap<u16, i32>,
+    remap_fn: Option<RemapFn>,
+    axis_fix: transform::AxisFix,
+    deadzone: transform::Deadzone,
+    smoothing: transform::Smoothing,
+    raw_axes: HashSet<u16>,
+    mixer: transform::Mixer,
+    flip: bool,
+    layer: Option<transform::Layer>,
+    sticky: transform::Sticky,
+    dpad_stick: Option<transform::DpadStick>,
+    two_stage: Option<transform::TwoStage>,
+}
+
+impl Pipeline {
+    pub fn new(config: &Config, device: Option<&DeviceConfig>, axes: &[(AbsoluteAxisType, AbsInfo)], remap_fn: Option<RemapFn>) -> Pipeline {
+        let (src_ranges, inverted_triggers) = crate::source_ranges(axes, device);
+        let raw_axes = transform::parse_axes(&config.raw_axes);
+        Pipeline {
+            src_ranges,
+            inverted_triggers,
+            remap_fn,
+            axis_fix: transform::AxisFix::new(device),
+            deadzone: transform::Deadzone::new(config.deadzone, &raw_axes),
+            smoothing: transform::Smoothing::new(&config.smoothing),
+            raw_axes,
+            mixer: transform::Mixer::new(&config.mix),
+            flip: device.is_some_and(|cfg| cfg.flip),
+            layer: config.modifier_layer.as_ref().map(|layer| transform::Layer::new(&layer.remap)),
+            sticky: transform::Sticky::new(&config.sticky),
+            dpad_stick: config.dpad_right_stick.as_deref()
+                .and_then(|name| remap::parse_key_alias(name).or_else(|| {
+                    log_info(format!("Ignoring unknown key {} in dpad_right_stick", name));
+                    None
+                }))
+                .map(transform::DpadStick::new),
+            two_stage: config.two_stage_triggers.as_ref().map(transform::TwoStage::new),
+        }
+    }
+
+    // forget whatever is latched or held, everything has been released anyway
+    pub fn reset(&mut self) {
+        self.sticky.reset();
+        if let Some(layer) = self.layer.as_mut() {
+            layer.reset();
+        }
+        if let Some(stages) = self.two_stage.as_mut() {
+            stages.reset();
+        }
+        if let Some(dpad) = self.dpad_stick.as_mut() {
+            dpad.reset();
+        }
+        self.smoothing.reset();
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        self.smoothing.due_in(now)
+    }
+
+    // what smoothed axes have moved on to since, when no events came in
+    pub fn poll(&mut self, now: Instant, config: &Config) -> Vec<InputEvent> {
+        let mut out = Vec::new();
+        let stepped = self.smoothing.poll(now);
+        self.finish_axes(stepped, config, &mut out);
+        out
+    }
+
+    // what a key or axis event of the device turns into, `modifier` being whether the modifier layer is active
+    pub fn process(&mut self, ev: InputEvent, modifier: bool, config: &Config, now: Instant) -> Vec<InputEvent> {
+        let mut out = Vec::new();
+        match ev.kind() {
+            InputEventKind::AbsAxis(axis) if crate::is_mt_axis(axis) => (),
+            InputEventKind::AbsAxis(axis) => {
+                let raw = match self.inverted_triggers.get(&axis.0) {
+                    Some(min_plus_max) => min_plus_max.saturating_sub(ev.value()),
+                    None => ev.value(),
+                };
+                // remaps pick the axis while the value is still raw, it's scaled from the source's range onto the target's
+                let mut remapped = InputEvent::new(EventType::ABSOLUTE, axis.0, raw);
+                if let Some(actual_remap_fn) = &self.remap_fn {
+                    actual_remap_fn(&mut remapped, &mut out);
+                }
+                let InputEventKind::AbsAxis(target) = remapped.kind() else {
+                    out.push(remapped);
+                    return out;
+                };
+                let val = crate::scale_onto(remapped.value(), self.src_ranges.get(&axis.0), target, config);
+                let fixed = self.axis_fix.apply(InputEvent::new(EventType::ABSOLUTE, target.0, val), config);
+                let filtered: Vec<InputEvent> = self.deadzone.apply(fixed)
+                    .into_iter()
+                    .map(|ev| if self.raw_axes.contains(&ev.code()) { ev } else { self.smoothing.apply(ev, now) })
+                    .collect();
+                self.finish_axes(filtered, config, &mut out);
+            },
+            InputEventKind::Key(_) => {
+                // keys are either down or up, autorepeat never makes it this far
+                let mut ev = InputEvent::new(EventType::KEY, ev.code(), (ev.value() != 0) as i32);
+                if let Some(actual_remap_fn) = &self.remap_fn {
+                    actual_remap_fn(&mut ev, &mut out);
+                }
+                if self.flip {
+                    ev = transform::flip(ev, config);
+                }
+                if let Some(layer) = self.layer.as_mut() {
+                    ev = layer.apply(ev, modifier);
+                }
+                if let Some(ev) = self.sticky.apply(ev) {
+                    // dpads reporting keys come out as hats by now
+                    self.finish(ev, &mut out);
+                }
+            },
+            _ => (),
+        }
+        out
+    }
+
+    // everything after smoothing
+    fn finish_axes(&mut self, events: Vec<InputEvent>, config: &Config, out: &mut Vec<InputEvent>) {
+        let mixed: Vec<InputEvent> = events.into_iter()
+            .flat_map(|ev| self.mixer.apply(ev, config))
+            .collect();
+        for mut ev in mixed {
+            if self.flip {
+                ev = transform::flip(ev, config);
+            }
+            self.finish(ev, out);
+        }
+    }
+
+    // the dpad stick and, for digital triggers remapped onto the trigger axes too, the two stage buttons
+    fn finish(&mut self, ev: InputEvent, out: &mut Vec<InputEvent>) {
+        for ev in self.dpad_stick.as_mut().map_or_else(|| vec![ev], |dpad| dpad.apply(ev)) {
+            out.push(ev);
+            out.extend(self.two_stage.as_mut().map(|stages| stages.apply(&ev)).unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::Key;
+    use proptest::prelude::*;
+    use crate::config::{MixConfig, MixInput, SmoothingConfig, TwoStageConfig};
+
+    // whether an event fits what the xbox pad declares
+    fn in_declared_range(ev: &InputEvent, config: &Config) -> bool {
+        match ev.kind() {
+            InputEventKind::Key(_) => (0..=1).contains(&ev.value()),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y) => (crate::MIN_OUT_HAT..=crate::MAX_OUT_HAT).contains(&ev.value()),
+            InputEventKind::AbsAxis(axis) => {
+                let (min, max) = crate::output_range(axis, config);
+                (min..=max).contains(&ev.value())
+            },
+            _ => false,
+        }
+    }
+
+    // the odd ranges real devices report: tiny, huge, inverted, zero width and hats bigger than they should be
+    fn axes() -> Vec<(AbsoluteAxisType, AbsInfo)> {
+        vec![
+            (AbsoluteAxisType::ABS_X, AbsInfo::new(0, 0, 255, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_Y, AbsInfo::new(0, -32768, 32767, 16, 128, 0)),
+            (AbsoluteAxisType::ABS_RX, AbsInfo::new(0, 7, 7, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_RY, AbsInfo::new(0, 1000, -1000, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_Z, AbsInfo::new(1023, 0, 1023, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_RZ, AbsInfo::new(1023, -1023, 1023, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_HAT0X, AbsInfo::new(0, -32768, 32767, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_HAT0Y, AbsInfo::new(0, 0, 0, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_GAS, AbsInfo::new(0, 0, 1, 0, 0, 0)),
+        ]
+    }
+
+    // everything that rewrites values switched on at once
+    fn busy_config(symmetric_analog: bool) -> Config {
+        Config {
+            symmetric_analog,
+            deadzone: 0.2,
+            smoothing: vec![SmoothingConfig { axis: "ABS_X".into(), threshold: 1000, factor: 0.3 }],
+            mix: vec![MixConfig {
+                output: "ABS_RX".into(),
+                inputs: vec![MixInput { axis: "ABS_X".into(), weight: 0.8 }, MixInput { axis: "ABS_RX".into(), weight: 0.8 }],
+            }],
+            two_stage_triggers: Some(TwoStageConfig { soft: 0.3, full: 0.9 }),
+            dpad_right_stick: Some("select".into()),
+            sticky: vec!["y".into()],
+            ..Config::default()
+        }
+    }
+
+    fn device(flip: bool) -> DeviceConfig {
+        DeviceConfig {
+            flip,
+            invert: vec!["ABS_Y".into(), "ABS_RZ".into()],
+            swap_sticks: true,
+            ..Default::default()
+        }
+    }
+
+    // random codes, mostly the ones that do something
+    fn event() -> impl Strategy<Value = (u16, u16, i32)> {
+        let types = prop::sample::select(vec![EventType::SYNCHRONIZATION.0, EventType::KEY.0, EventType::ABSOLUTE.0, EventType::MISC.0]);
+        (types, 0u16..0x300, any::<i32>())
+    }
+
+    proptest! {
+        #[test]
+        fn everything_stays_in_range(
+            events in prop::collection::vec(event(), 0..64),
+            symmetric in any::<bool>(),
+            flip in any::<bool>(),
+            quirk in any::<bool>(),
+        ) {
+            let config = busy_config(symmetric);
+            let remap_fn: Option<RemapFn> = match quirk {
+                true => Some(Box::new(crate::generic_dac)),
+                false => None,
+            };
+            let mut pipeline = Pipeline::new(&config, Some(&device(flip)), &axes(), remap_fn);
+            let mut now = Instant::now();
+            for (type_, code, value) in events {
+                // axes mostly near their own range, or anywhere at all
+                let value = if type_ == EventType::ABSOLUTE.0 && value % 4 != 0 { value % 40000 } else { value };
+                let ev = InputEvent::new(EventType(type_), code, value);
+                // smoothing steps on between some of the events
+                now += Duration::from_millis(value.rem_euclid(16) as u64);
+                let mut out = pipeline.process(ev, code % 2 == 0, &config, now);
+                out.append(&mut pipeline.poll(now, &config));
+                for out in out {
+                    prop_assert!(in_declared_range(&out, &config),
+                        "{}:{} value {} from {}:{} value {}", out.event_type().0, out.code(), out.value(), type_, code, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keys_go_through_untouched() {
+        let config = Config::default();
+        let mut pipeline = Pipeline::new(&config, None, &[], None);
+        let out = pipeline.process(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, 1), false, &config, Instant::now());
+        assert_eq!(out.iter().map(|ev| (ev.code(), ev.value())).collect::<Vec<_>>(), [(Key::BTN_SOUTH.0, 1)]);
+    }
+
+    #[test]
+    fn multitouch_is_dropped() {
+        let config = Config::default();
+        let mut pipeline = Pipeline::new(&config, None, &[], None);
+        assert!(pipeline.process(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_MT_POSITION_X.0, 100), false, &config, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn raw_axes_skip_the_filters() {
+        let config = Config {
+            deadzone: 0.2,
+            smoothing: vec![
+                SmoothingConfig { axis: "ABS_RX".into(), threshold: 1000, factor: 0.5 },
+                SmoothingConfig { axis: "ABS_Z".into(), threshold: 10, factor: 0.5 },
+            ],
+            raw_axes: vec!["ABS_Z".into(), "ABS_Y".into()],
+            ..Config::default()
+        };
+        let axes = [
+            (AbsoluteAxisType::ABS_Y, AbsInfo::new(0, -32768, 32767, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_RX, AbsInfo::new(0, -32768, 32767, 0, 0, 0)),
+            (AbsoluteAxisType::ABS_Z, AbsInfo::new(0, 0, 255, 0, 0, 0)),
+        ];
+        let mut pipeline = Pipeline::new(&config, None, &axes, None);
+        let now = Instant::now();
+        let mut out = |axis: AbsoluteAxisType, value| -> Vec<(u16, i32)> {
+            pipeline.process(InputEvent::new(EventType::ABSOLUTE, axis.0, value), false, &config, now)
+                .iter().map(|ev| (ev.code(), ev.value())).collect()
+        };
+
+        out(AbsoluteAxisType::ABS_Z, 0);
+        assert_eq!(out(AbsoluteAxisType::ABS_Z, 255), [(AbsoluteAxisType::ABS_Z.0, 255)]);
+        out(AbsoluteAxisType::ABS_RX, 0);
+        assert_eq!(out(AbsoluteAxisType::ABS_RX, 32767), [(AbsoluteAxisType::ABS_RX.0, 16384), (AbsoluteAxisType::ABS_RY.0, 0)]);
+        // no deadzone either, the left stick's other half is as raw as it gets
+        assert_eq!(out(AbsoluteAxisType::ABS_Y, 1000), [(AbsoluteAxisType::ABS_Y.0, 1000)]);
+    }
+
+    #[test]
+    fn inverted_triggers_rise_as_they_are_pulled() {
+        let config = Config::default();
+        let device = DeviceConfig { invert_triggers: Some(true), ..Default::default() };
+        let axes = [(AbsoluteAxisType::ABS_Z, AbsInfo::new(255, 0, 255, 0, 0, 0))];
+        let mut pipeline = Pipeline::new(&config, Some(&device), &axes, None);
+        let now = Instant::now();
+
+        // resting at the maximum, pulled all the way towards 0
+        let out: Vec<i32> = [255, 192, 128, 64, 0].into_iter()
+            .flat_map(|raw| pipeline.process(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, raw), false, &config, now))
+            .map(|ev| ev.value())
+            .collect();
+        assert_eq!(out.first(), Some(&0));
+        assert_eq!(out.last(), Some(&crate::max_out_trig()));
+        assert!(out.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", out);
+    }
+}
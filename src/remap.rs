@@ -0,0 +1,288 @@
+use evdev::{
+    AbsoluteAxisType,
+    InputEventKind,
+    InputEvent,
+    EventType,
+    Key,
+};
+use std::collections::HashMap;
+use libdogd::log_info;
+use crate::config::DeviceProfile;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Target {
+    Key(Key),
+    // axis value while the source key is pressed and released respectively
+    Abs(AbsoluteAxisType, i32, i32),
+}
+
+// table-driven counterpart of the hardcoded quirk functions
+#[derive(Default, Debug)]
+pub struct RemapTable {
+    keys: HashMap<u16, Target>,
+    axes: HashMap<u16, AbsoluteAxisType>,
+}
+
+impl RemapTable {
+    pub fn map_key(&mut self, from: Key, to: Target) {
+        self.keys.insert(from.0, to);
+    }
+
+    pub fn map_axis(&mut self, from: AbsoluteAxisType, to: AbsoluteAxisType) {
+        self.axes.insert(from.0, to);
+    }
+
+    pub fn from_profile(profile: &DeviceProfile) -> RemapTable {
+        let mut table = RemapTable::default();
+        for (from, to) in &profile.keys {
+            match (parse_key_alias(from), parse_target(to)) {
+                (Some(from), Some(to)) => table.map_key(from, to),
+                _ => log_info(format!("{}: ignoring key mapping {} -> {}", profile.source.display(), from, to)),
+            }
+        }
+        for (from, to) in &profile.axes {
+            match (parse_code(from), parse_code(to)) {
+                (Some(Code::Abs(from)), Some(Code::Abs(to))) => table.map_axis(from, to),
+                _ => log_info(format!("{}: ignoring axis mapping {} -> {}", profile.source.display(), from, to)),
+            }
+        }
+        table
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.axes.is_empty()
+    }
+
+    pub fn apply(&self, ev: &mut InputEvent) {
+        match ev.kind() {
+            InputEventKind::Key(key) => match self.keys.get(&key.0) {
+                Some(Target::Key(to)) => *ev = InputEvent::new(EventType::KEY, to.0, ev.value()),
+                Some(Target::Abs(axis, on, off)) => {
+                    *ev = InputEvent::new(EventType::ABSOLUTE, axis.0, if ev.value() == 0 { *off } else { *on });
+                },
+                None => (),
+            },
+            // axes are remapped while still raw, the value is scaled from the source's range onto the target's later
+            InputEventKind::AbsAxis(axis) => if let Some(to) = self.axes.get(&axis.0) {
+                *ev = InputEvent::new(EventType::ABSOLUTE, to.0, ev.value());
+            },
+            _ => (),
+        }
+    }
+}
+
+// accepts the usual gamepad BTN_* names as well as raw numeric codes
+pub fn parse_key(name: &str) -> Option<Key> {
+    let key = match name {
+        "BTN_SOUTH" | "BTN_A"   => Key::BTN_SOUTH,
+        "BTN_EAST"  | "BTN_B"   => Key::BTN_EAST,
+        "BTN_C"                 => Key::BTN_C,
+        "BTN_NORTH" | "BTN_X"   => Key::BTN_NORTH,
+        "BTN_WEST"  | "BTN_Y"   => Key::BTN_WEST,
+        "BTN_Z"                 => Key::BTN_Z,
+        "BTN_TL"                => Key::BTN_TL,
+        "BTN_TR"                => Key::BTN_TR,
+        "BTN_TL2"               => Key::BTN_TL2,
+        "BTN_TR2"               => Key::BTN_TR2,
+        "BTN_SELECT"            => Key::BTN_SELECT,
+        "BTN_START"             => Key::BTN_START,
+        "BTN_MODE"              => Key::BTN_MODE,
+        "BTN_THUMBL"            => Key::BTN_THUMBL,
+        "BTN_THUMBR"            => Key::BTN_THUMBR,
+        "BTN_DPAD_UP"           => Key::BTN_DPAD_UP,
+        "BTN_DPAD_DOWN"         => Key::BTN_DPAD_DOWN,
+        "BTN_DPAD_LEFT"         => Key::BTN_DPAD_LEFT,
+        "BTN_DPAD_RIGHT"        => Key::BTN_DPAD_RIGHT,
+        "BTN_TRIGGER_HAPPY1"    => Key::BTN_TRIGGER_HAPPY1,
+        "BTN_TRIGGER_HAPPY2"    => Key::BTN_TRIGGER_HAPPY2,
+        "BTN_TRIGGER_HAPPY3"    => Key::BTN_TRIGGER_HAPPY3,
+        "BTN_TRIGGER_HAPPY4"    => Key::BTN_TRIGGER_HAPPY4,
+        "BTN_TRIGGER_HAPPY5"    => Key::BTN_TRIGGER_HAPPY5,
+        "BTN_TRIGGER_HAPPY6"    => Key::BTN_TRIGGER_HAPPY6,
+        "BTN_TRIGGER_HAPPY7"    => Key::BTN_TRIGGER_HAPPY7,
+        "BTN_TRIGGER_HAPPY8"    => Key::BTN_TRIGGER_HAPPY8,
+        // media and navigation keys handhelds tend to have on a separate keyboard node
+        "KEY_VOLUMEUP"          => Key::KEY_VOLUMEUP,
+        "KEY_VOLUMEDOWN"        => Key::KEY_VOLUMEDOWN,
+        "KEY_MUTE"              => Key::KEY_MUTE,
+        "KEY_POWER"             => Key::KEY_POWER,
+        "KEY_HOME"              => Key::KEY_HOME,
+        "KEY_HOMEPAGE"          => Key::KEY_HOMEPAGE,
+        "KEY_MENU"              => Key::KEY_MENU,
+        "KEY_BACK"              => Key::KEY_BACK,
+        "KEY_PLAYPAUSE"         => Key::KEY_PLAYPAUSE,
+        _ => {
+            let code = match name.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+                None => name.parse().ok()?,
+            };
+            Key(code)
+        },
+    };
+    Some(key)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    Key(Key),
+    Abs(AbsoluteAxisType),
+}
+
+pub fn parse_abs(name: &str) -> Option<AbsoluteAxisType> {
+    Some(match name {
+        "ABS_X"        => AbsoluteAxisType::ABS_X,
+        "ABS_Y"        => AbsoluteAxisType::ABS_Y,
+        "ABS_Z"        => AbsoluteAxisType::ABS_Z,
+        "ABS_RX"       => AbsoluteAxisType::ABS_RX,
+        "ABS_RY"       => AbsoluteAxisType::ABS_RY,
+        "ABS_RZ"       => AbsoluteAxisType::ABS_RZ,
+        "ABS_THROTTLE" => AbsoluteAxisType::ABS_THROTTLE,
+        "ABS_RUDDER"   => AbsoluteAxisType::ABS_RUDDER,
+        "ABS_WHEEL"    => AbsoluteAxisType::ABS_WHEEL,
+        "ABS_GAS"      => AbsoluteAxisType::ABS_GAS,
+        "ABS_BRAKE"    => AbsoluteAxisType::ABS_BRAKE,
+        "ABS_HAT0X"    => AbsoluteAxisType::ABS_HAT0X,
+        "ABS_HAT0Y"    => AbsoluteAxisType::ABS_HAT0Y,
+        _ => return None,
+    })
+}
+
+// readable names for config files, on top of the raw BTN_*/ABS_* ones
+pub fn parse_code(name: &str) -> Option<Code> {
+    let code = match name.to_ascii_lowercase().as_str() {
+        "a"                 => Code::Key(Key::BTN_SOUTH),
+        "b"                 => Code::Key(Key::BTN_EAST),
+        "x"                 => Code::Key(Key::BTN_NORTH),
+        "y"                 => Code::Key(Key::BTN_WEST),
+        "lb"                => Code::Key(Key::BTN_TL),
+        "rb"                => Code::Key(Key::BTN_TR),
+        "l3"                => Code::Key(Key::BTN_THUMBL),
+        "r3"                => Code::Key(Key::BTN_THUMBR),
+        "select" | "back"   => Code::Key(Key::BTN_SELECT),
+        "start"             => Code::Key(Key::BTN_START),
+        "guide" | "home"    => Code::Key(Key::BTN_MODE),
+        "dpad_up"           => Code::Key(Key::BTN_DPAD_UP),
+        "dpad_down"         => Code::Key(Key::BTN_DPAD_DOWN),
+        "dpad_left"         => Code::Key(Key::BTN_DPAD_LEFT),
+        "dpad_right"        => Code::Key(Key::BTN_DPAD_RIGHT),
+        "lt"                => Code::Abs(AbsoluteAxisType::ABS_Z),
+        "rt"                => Code::Abs(AbsoluteAxisType::ABS_RZ),
+        "lstick_x"          => Code::Abs(AbsoluteAxisType::ABS_X),
+        "lstick_y"          => Code::Abs(AbsoluteAxisType::ABS_Y),
+        "rstick_x"          => Code::Abs(AbsoluteAxisType::ABS_RX),
+        "rstick_y"          => Code::Abs(AbsoluteAxisType::ABS_RY),
+        "dpad_x"            => Code::Abs(AbsoluteAxisType::ABS_HAT0X),
+        "dpad_y"            => Code::Abs(AbsoluteAxisType::ABS_HAT0Y),
+        _ => return parse_abs(name).map(Code::Abs).or_else(|| parse_key(name).map(Code::Key)),
+    };
+    Some(code)
+}
+
+pub fn parse_key_alias(name: &str) -> Option<Key> {
+    match parse_code(name) {
+        Some(Code::Key(key)) => Some(key),
+        _ => None,
+    }
+}
+
+// a key or an axis with optional values on press and release, e.g. "ABS_HAT0X -1 0",
+// axes default to full deflection on press and 0 on release
+fn parse_target(spec: &str) -> Option<Target> {
+    let mut parts = spec.split_whitespace();
+    match parse_code(parts.next()?)? {
+        Code::Key(key) => Some(Target::Key(key)),
+        Code::Abs(axis) => {
+            let full = if crate::is_trigger(axis) { crate::max_out_trig() } else { crate::MAX_OUT_ANALOG };
+            let on = parts.next().map(str::parse).transpose().ok()?.unwrap_or(full);
+            let off = parts.next().map(str::parse).transpose().ok()?.unwrap_or(0);
+            Some(Target::Abs(axis, on, off))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases() {
+        assert_eq!(parse_code("a"), Some(Code::Key(Key::BTN_SOUTH)));
+        assert_eq!(parse_code("Select"), Some(Code::Key(Key::BTN_SELECT)));
+        assert_eq!(parse_code("home"), Some(Code::Key(Key::BTN_MODE)));
+        assert_eq!(parse_code("lt"), Some(Code::Abs(AbsoluteAxisType::ABS_Z)));
+        assert_eq!(parse_code("rstick_y"), Some(Code::Abs(AbsoluteAxisType::ABS_RY)));
+        assert_eq!(parse_code("dpad_x"), Some(Code::Abs(AbsoluteAxisType::ABS_HAT0X)));
+    }
+
+    #[test]
+    fn raw_names_and_codes() {
+        assert_eq!(parse_code("BTN_TL2"), Some(Code::Key(Key::BTN_TL2)));
+        assert_eq!(parse_code("ABS_RX"), Some(Code::Abs(AbsoluteAxisType::ABS_RX)));
+        assert_eq!(parse_code("0x130"), Some(Code::Key(Key::BTN_SOUTH)));
+        assert_eq!(parse_code("305"), Some(Code::Key(Key::BTN_EAST)));
+        assert_eq!(parse_code("nonsense"), None);
+        assert_eq!(parse_key_alias("lt"), None);
+    }
+
+    #[test]
+    fn targets() {
+        assert!(matches!(parse_target("b"), Some(Target::Key(Key::BTN_EAST))));
+        assert!(matches!(parse_target("ABS_HAT0X -1 0"), Some(Target::Abs(AbsoluteAxisType::ABS_HAT0X, -1, 0))));
+        assert!(matches!(parse_target("rt"), Some(Target::Abs(AbsoluteAxisType::ABS_RZ, 255, 0))));
+        assert!(parse_target("ABS_Z full").is_none());
+    }
+
+    fn profile(keys: &[(&str, &str)], axes: &[(&str, &str)]) -> DeviceProfile {
+        let owned = |pairs: &[(&str, &str)]| pairs.iter().map(|(from, to)| (from.to_string(), to.to_string())).collect();
+        DeviceProfile {
+            id: "1209:3100".into(),
+            path: None,
+            keys: owned(keys),
+            axes: owned(axes),
+            source: "test.toml".into(),
+        }
+    }
+
+    fn remapped(table: &RemapTable, type_: EventType, code: u16, value: i32) -> (u16, u16, i32) {
+        let mut ev = InputEvent::new(type_, code, value);
+        table.apply(&mut ev);
+        (ev.event_type().0, ev.code(), ev.value())
+    }
+
+    #[test]
+    fn profile_tables() {
+        let table = RemapTable::from_profile(&profile(
+            &[("BTN_EAST", "a"), ("BTN_TL2", "ABS_Z 255 0"), ("nonsense", "a")],
+            &[("ABS_RX", "lt"), ("ABS_X", "ABS_HAT0X"), ("ABS_Y", "b")],
+        ));
+        assert_eq!(remapped(&table, EventType::KEY, Key::BTN_EAST.0, 1), (EventType::KEY.0, Key::BTN_SOUTH.0, 1));
+        assert_eq!(remapped(&table, EventType::KEY, Key::BTN_TL2.0, 1), (EventType::ABSOLUTE.0, AbsoluteAxisType::ABS_Z.0, 255));
+        assert_eq!(remapped(&table, EventType::KEY, Key::BTN_TL2.0, 0), (EventType::ABSOLUTE.0, AbsoluteAxisType::ABS_Z.0, 0));
+        // axes keep their raw value, main scales it onto the target afterwards
+        assert_eq!(remapped(&table, EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, -30000), (EventType::ABSOLUTE.0, AbsoluteAxisType::ABS_Z.0, -30000));
+        assert_eq!(remapped(&table, EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 200), (EventType::ABSOLUTE.0, AbsoluteAxisType::ABS_HAT0X.0, 200));
+        // an axis can't become a key
+        assert_eq!(remapped(&table, EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, 7), (EventType::ABSOLUTE.0, AbsoluteAxisType::ABS_Y.0, 7));
+        assert!(RemapTable::from_profile(&profile(&[], &[])).is_empty());
+    }
+
+    #[test]
+    fn profile_axes_end_up_in_the_targets_range() {
+        let table = RemapTable::from_profile(&profile(&[], &[("ABS_RX", "ABS_Z"), ("ABS_X", "ABS_HAT0X")]));
+        let config = crate::config::Config::default();
+        let stick = evdev::AbsInfo::new(0, -32768, 32767, 0, 0, 0);
+        let remap_fn: crate::RemapFn = Box::new(move |ev, _| table.apply(ev));
+        let axes = [(AbsoluteAxisType::ABS_X, stick), (AbsoluteAxisType::ABS_RX, stick)];
+        let mut pipeline = crate::pipeline::Pipeline::new(&config, None, &axes, Some(remap_fn));
+        let now = std::time::Instant::now();
+
+        let out = |pipeline: &mut crate::pipeline::Pipeline, axis: AbsoluteAxisType, value| -> Vec<(u16, i32)> {
+            pipeline.process(InputEvent::new(EventType::ABSOLUTE, axis.0, value), false, &config, now)
+                .iter().map(|ev| (ev.code(), ev.value())).collect()
+        };
+        assert_eq!(out(&mut pipeline, AbsoluteAxisType::ABS_RX, 32767), [(AbsoluteAxisType::ABS_Z.0, crate::max_out_trig())]);
+        assert_eq!(out(&mut pipeline, AbsoluteAxisType::ABS_RX, -32768), [(AbsoluteAxisType::ABS_Z.0, 0)]);
+        assert_eq!(out(&mut pipeline, AbsoluteAxisType::ABS_X, -32768), [(AbsoluteAxisType::ABS_HAT0X.0, -1)]);
+        assert_eq!(out(&mut pipeline, AbsoluteAxisType::ABS_X, 32767), [(AbsoluteAxisType::ABS_HAT0X.0, 1)]);
+    }
+}
@@ -0,0 +1,216 @@
+use anyhow::Result;
+use evdev::{
+    Device,
+    FFEffect,
+    FFEffectData,
+    FFEffectType,
+    InputEvent,
+    InputEventKind,
+    UInputEventType,
+    uinput::VirtualDevice,
+};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use std::{
+    collections::HashMap,
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak, atomic::{AtomicBool, Ordering}},
+    thread,
+};
+use libdogd::log_debug;
+use crate::shutdown;
+
+// games rarely use more than a couple
+pub const MAX_EFFECTS: u32 = 16;
+
+// a second handle on a pad that can rumble, the handler thread keeps reading from its own
+struct Rumbler {
+    // virtual pad whose effects it plays
+    pad: usize,
+    // declared first so they are erased while the device is still open
+    effects: HashMap<i16, FFEffect>,
+    dev: Device,
+}
+
+// effects games uploaded to the virtual pads, mirrored onto every physical pad behind them that can play them
+#[derive(Default)]
+pub struct Rumblers {
+    uploaded: Mutex<HashMap<(usize, i16), FFEffectData>>,
+    devices: Mutex<HashMap<PathBuf, Rumbler>>,
+}
+
+impl Rumblers {
+    // pads without FF_RUMBLE are left out, their share of the effects just gets dropped
+    pub fn register(&self, path: &Path, name: &str, pad: usize) {
+        let dev = match Device::open(path) {
+            Ok(dev) => dev,
+            Err(_) => return,
+        };
+        if !dev.supported_ff().is_some_and(|ff| ff.contains(FFEffectType::FF_RUMBLE)) {
+            return;
+        }
+        log_debug(format!("Forwarding rumble to {}", name));
+        self.devices.lock().unwrap().insert(path.to_path_buf(), Rumbler {
+            pad,
+            effects: HashMap::new(),
+            dev,
+        });
+    }
+
+    pub fn unregister(&self, path: &Path) {
+        self.devices.lock().unwrap().remove(path);
+    }
+
+    // effect ids belong to the virtual device, a new one starts with none
+    pub fn clear(&self, pad: usize) {
+        self.uploaded.lock().unwrap().retain(|(of, _), _| *of != pad);
+        for rumbler in self.devices.lock().unwrap().values_mut().filter(|rumbler| rumbler.pad == pad) {
+            rumbler.effects.clear();
+        }
+    }
+
+    fn upload(&self, pad: usize, id: i16, data: FFEffectData) {
+        self.uploaded.lock().unwrap().insert((pad, id), data);
+        for (path, rumbler) in self.devices.lock().unwrap().iter_mut().filter(|(_, rumbler)| rumbler.pad == pad) {
+            // overwriting a slot updates the effect in place instead of taking another one
+            let ret = match rumbler.effects.get_mut(&id) {
+                Some(effect) => effect.update(data),
+                None => rumbler.dev.upload_ff_effect(data)
+                    .map(|effect| { rumbler.effects.insert(id, effect); }),
+            };
+            if let Err(e) = ret {
+                log_debug(format!("{} rejected effect {}: {}", path.display(), id, e));
+            }
+        }
+    }
+
+    fn erase(&self, pad: usize, id: i16) {
+        self.uploaded.lock().unwrap().remove(&(pad, id));
+        for rumbler in self.devices.lock().unwrap().values_mut().filter(|rumbler| rumbler.pad == pad) {
+            rumbler.effects.remove(&id);
+        }
+    }
+
+    fn play(&self, pad: usize, id: i16, count: i32) {
+        let data = match self.uploaded.lock().unwrap().get(&(pad, id)) {
+            Some(data) => *data,
+            None => return,
+        };
+        for (path, rumbler) in self.devices.lock().unwrap().iter_mut().filter(|(_, rumbler)| rumbler.pad == pad) {
+            // pads plugged in after the upload get the effect the first time it plays
+            if !rumbler.effects.contains_key(&id) {
+                match rumbler.dev.upload_ff_effect(data) {
+                    Ok(effect) => { rumbler.effects.insert(id, effect); },
+                    Err(_) => continue,
+                }
+            }
+            let effect = rumbler.effects.get_mut(&id).unwrap();
+            let ret = match count {
+                0 => effect.stop(),
+                count => effect.play(count),
+            };
+            if let Err(e) = ret {
+                log_debug(format!("Failed to play effect {} on {}: {}", id, path.display(), e));
+            }
+        }
+    }
+}
+
+// a wakeup may turn out to have nothing to read, the listener must never wait on it
+pub fn set_nonblocking(uhandle: &VirtualDevice) -> Result<()> {
+    let fd = uhandle.as_raw_fd();
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+// handle whatever games asked of virtual pad `pad` since last time, only failing to read at all is an error
+fn process(uhandle: &mut VirtualDevice, rumblers: &Rumblers, pad: usize) -> Result<()> {
+    let events: Vec<InputEvent> = match uhandle.fetch_events() {
+        Ok(events) => events.collect(),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for ev in events {
+        match ev.kind() {
+            InputEventKind::UInput(code) if code == UInputEventType::UI_FF_UPLOAD.0 => match uhandle.process_ff_upload(ev) {
+                Ok(mut upload) => {
+                    rumblers.upload(pad, upload.effect_id(), upload.effect());
+                    // the game doesn't need to know nothing can play it, completed on drop
+                    upload.set_retval(0);
+                },
+                Err(e) => log_debug(format!("Failed to take an effect upload for pad {}: {}", pad + 1, e)),
+            },
+            InputEventKind::UInput(code) if code == UInputEventType::UI_FF_ERASE.0 => match uhandle.process_ff_erase(ev) {
+                Ok(mut erase) => {
+                    rumblers.erase(pad, erase.effect_id() as i16);
+                    erase.set_retval(0);
+                },
+                Err(e) => log_debug(format!("Failed to take an effect erase for pad {}: {}", pad + 1, e)),
+            },
+            InputEventKind::ForceFeedback(id) => rumblers.play(pad, id as i16, ev.value()),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+// nothing tells main when a game wants something of a virtual pad, so each one gets a thread waiting on it
+pub struct Listener {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Listener {
+    pub fn start(uhandle: &Arc<Mutex<VirtualDevice>>, rumblers: Arc<Rumblers>, pad: usize) -> Listener {
+        let stop = Arc::new(AtomicBool::new(false));
+        // the fd is only waited on, all reading happens under the lock
+        let fd = Fd(uhandle.lock().unwrap().as_raw_fd());
+        let uhandle = Arc::downgrade(uhandle);
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || listen(fd, uhandle, &rumblers, pad, &stopped));
+        Listener {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+// joined before the device goes, so the fd is never waited on once it's closed
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn listen(fd: Fd, uhandle: Weak<Mutex<VirtualDevice>>, rumblers: &Rumblers, pad: usize, stop: &AtomicBool) {
+    loop {
+        match shutdown::wait_readable_for(&fd, Some(shutdown::POLL_INTERVAL)) {
+            Ok(shutdown::Wait::Readable) => (),
+            Ok(shutdown::Wait::Timeout) if !stop.load(Ordering::Relaxed) => continue,
+            _ => return,
+        }
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(device) = uhandle.upgrade() else { return };
+        let ret = process(&mut device.lock().unwrap(), rumblers, pad);
+        if let Err(e) = ret {
+            log_debug(format!("No more rumble for pad {}: {}", pad + 1, e));
+            return;
+        }
+    }
+}
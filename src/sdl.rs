@@ -0,0 +1,172 @@
+use evdev::{
+    AbsoluteAxisType,
+    InputId,
+    Device,
+    Key,
+};
+use std::env;
+use libdogd::log_debug;
+use crate::remap::{RemapTable, Target};
+
+// SDL GUIDs are bus, vendor, product and version as little endian u16s, each padded to 32 bits
+fn guid(id: InputId) -> String {
+    [id.bus_type().0, id.vendor(), id.product(), id.version()].iter()
+        .map(|v| format!("{:02x}{:02x}0000", v & 0xff, v >> 8))
+        .collect()
+}
+
+// newer SDL versions stash a CRC of the device name in the second u16, ignore it
+fn guid_matches(mapping: &str, ours: &str) -> bool {
+    let mapping = mapping.to_ascii_lowercase();
+    mapping.len() == 32 && mapping[..4] == ours[..4] && mapping[8..] == ours[8..]
+}
+
+// button and axis indices the way SDL's linux backend hands them out
+fn sdl_buttons(dev: &Device) -> Vec<Key> {
+    let Some(keys) = dev.supported_keys() else { return Vec::new() };
+    let mut buttons: Vec<Key> = keys.iter().filter(|key| key.0 >= Key::BTN_JOYSTICK.0).collect();
+    buttons.extend(keys.iter().filter(|key| key.0 < Key::BTN_JOYSTICK.0));
+    buttons
+}
+
+fn sdl_axes(dev: &Device) -> Vec<AbsoluteAxisType> {
+    let Some(axes) = dev.supported_absolute_axes() else { return Vec::new() };
+    // hats are exposed separately as hN
+    axes.iter()
+        .filter(|axis| !(AbsoluteAxisType::ABS_HAT0X.0..=AbsoluteAxisType::ABS_HAT3Y.0).contains(&axis.0))
+        .collect()
+}
+
+fn button_target(name: &str) -> Option<Target> {
+    let key = match name {
+        "a"             => Key::BTN_SOUTH,
+        "b"             => Key::BTN_EAST,
+        "x"             => Key::BTN_NORTH,
+        "y"             => Key::BTN_WEST,
+        "back"          => Key::BTN_SELECT,
+        "start"         => Key::BTN_START,
+        "guide"         => Key::BTN_MODE,
+        "leftshoulder"  => Key::BTN_TL,
+        "rightshoulder" => Key::BTN_TR,
+        "leftstick"     => Key::BTN_THUMBL,
+        "rightstick"    => Key::BTN_THUMBR,
+        "dpup"          => return Some(Target::Abs(AbsoluteAxisType::ABS_HAT0Y, -1, 0)),
+        "dpdown"        => return Some(Target::Abs(AbsoluteAxisType::ABS_HAT0Y,  1, 0)),
+        "dpleft"        => return Some(Target::Abs(AbsoluteAxisType::ABS_HAT0X, -1, 0)),
+        "dpright"       => return Some(Target::Abs(AbsoluteAxisType::ABS_HAT0X,  1, 0)),
+        "lefttrigger"   => return Some(Target::Abs(AbsoluteAxisType::ABS_Z, crate::max_out_trig(), crate::MIN_OUT_TRIG)),
+        "righttrigger"  => return Some(Target::Abs(AbsoluteAxisType::ABS_RZ, crate::max_out_trig(), crate::MIN_OUT_TRIG)),
+        _ => return None,
+    };
+    Some(Target::Key(key))
+}
+
+fn axis_target(name: &str) -> Option<AbsoluteAxisType> {
+    Some(match name {
+        "leftx"        => AbsoluteAxisType::ABS_X,
+        "lefty"        => AbsoluteAxisType::ABS_Y,
+        "rightx"       => AbsoluteAxisType::ABS_RX,
+        "righty"       => AbsoluteAxisType::ABS_RY,
+        "lefttrigger"  => AbsoluteAxisType::ABS_Z,
+        "righttrigger" => AbsoluteAxisType::ABS_RZ,
+        _ => return None,
+    })
+}
+
+fn parse_mapping(mapping: &str, buttons: &[Key], axes: &[AbsoluteAxisType]) -> RemapTable {
+    let mut table = RemapTable::default();
+
+    // skip the guid and name
+    for element in mapping.split(',').skip(2) {
+        let Some((output, input)) = element.split_once(':') else { continue };
+        let index = |prefix| input.strip_prefix(prefix).and_then(|i: &str| i.parse::<usize>().ok());
+
+        if let Some(key) = index("b").and_then(|i| buttons.get(i)) {
+            match button_target(output) {
+                Some(target) => table.map_key(*key, target),
+                None => log_debug(format!("Ignoring SDL mapping element {}", element)),
+            }
+        } else if let Some(axis) = index("a").and_then(|i| axes.get(i)) {
+            match axis_target(output) {
+                Some(target) => table.map_axis(*axis, target),
+                None => log_debug(format!("Ignoring SDL mapping element {}", element)),
+            }
+        } else if !input.starts_with('h') && output != "platform" {
+            // hats already come out as hats, anything else (half axes, inversion) is unsupported
+            log_debug(format!("Ignoring SDL mapping element {}", element));
+        }
+    }
+    table
+}
+
+// the line of SDL_GAMECONTROLLERCONFIG for a device, one mapping per line
+fn find_mapping(mappings: &str, id: InputId) -> Option<&str> {
+    let ours = guid(id);
+    mappings.lines()
+        .map(str::trim)
+        .find(|line| line.split(',').next().is_some_and(|guid| guid_matches(guid, &ours)))
+}
+
+// remaps for `dev` from SDL_GAMECONTROLLERCONFIG, if the user has one for it
+pub fn remap_for(dev: &Device) -> Option<RemapTable> {
+    let mappings = env::var("SDL_GAMECONTROLLERCONFIG").ok()?;
+    let mapping = find_mapping(&mappings, dev.input_id())?;
+
+    let table = parse_mapping(mapping, &sdl_buttons(dev), &sdl_axes(dev));
+    if table.is_empty() {
+        None
+    } else {
+        Some(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{BusType, EventType, InputEvent};
+
+    fn rg351m_id() -> InputId {
+        InputId::new(BusType::BUS_USB, 0x1209, 0x3100, 0x0111)
+    }
+
+    #[test]
+    fn guid_layout() {
+        assert_eq!(guid(rg351m_id()), "03000000091200000031000011010000");
+    }
+
+    #[test]
+    fn guid_ignores_name_crc() {
+        let ours = guid(rg351m_id());
+        assert!(guid_matches("0300ABCD091200000031000011010000", &ours));
+        assert!(!guid_matches("03000000091200000032000011010000", &ours));
+        assert!(!guid_matches("030000000912", &ours));
+    }
+
+    #[test]
+    fn mapping_from_env_var_is_applied() {
+        let mappings = "\
+            030000005e0400008e02000014010000,Xbox 360,a:b0,b:b1,platform:Linux,
+            03000000091200000031000011010000,RG351M,a:b1,b:b0,lefttrigger:a2,leftx:a0,platform:Linux,";
+        let mapping = find_mapping(mappings, rg351m_id()).unwrap();
+        assert!(mapping.contains("RG351M"));
+
+        let buttons = [Key::BTN_SOUTH, Key::BTN_EAST];
+        let axes = [AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y, AbsoluteAxisType::ABS_RX];
+        let table = parse_mapping(mapping, &buttons, &axes);
+
+        let mut ev = InputEvent::new(EventType::KEY, Key::BTN_EAST.0, 1);
+        table.apply(&mut ev);
+        assert_eq!((ev.event_type(), ev.code(), ev.value()), (EventType::KEY, Key::BTN_SOUTH.0, 1));
+
+        // axes keep their raw value, scaling onto the target comes afterwards
+        let mut ev = InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, 900);
+        table.apply(&mut ev);
+        assert_eq!((ev.code(), ev.value()), (AbsoluteAxisType::ABS_Z.0, 900));
+    }
+
+    #[test]
+    fn no_mapping_for_other_devices() {
+        let mappings = "030000005e0400008e02000014010000,Xbox 360,a:b0,platform:Linux,";
+        assert_eq!(find_mapping(mappings, rg351m_id()), None);
+    }
+}
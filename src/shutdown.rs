@@ -0,0 +1,63 @@
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+use std::{
+    os::unix::io::AsRawFd,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+// how long anything blocking goes without looking at the flag
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: i32) {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+// SIGINT and SIGTERM only ask everyone to wind down, main exits once the devices are let go
+pub fn install() -> Result<()> {
+    let action = SigAction::new(SigHandler::Handler(on_signal), SaFlags::SA_RESTART, SigSet::empty());
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // the handler only touches an atomic
+        unsafe { sigaction(signal, &action) }?;
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+pub enum Wait {
+    Readable,
+    Timeout,
+    Shutdown,
+}
+
+// fetch_events blocks indefinitely, so wait for something to read here first,
+// false means we're shutting down and shouldn't read at all
+pub fn wait_readable(dev: &impl AsRawFd) -> Result<bool> {
+    Ok(matches!(wait_readable_for(dev, None)?, Wait::Readable))
+}
+
+// same, but giving up after `timeout` if there is one
+pub fn wait_readable_for(dev: &impl AsRawFd, timeout: Option<Duration>) -> Result<Wait> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut fds = [PollFd::new(dev.as_raw_fd(), PollFlags::POLLIN)];
+    while !requested() {
+        let left = deadline.map_or(POLL_INTERVAL, |deadline| deadline.saturating_duration_since(Instant::now()).min(POLL_INTERVAL));
+        // rounded up, waking just before the deadline would only mean polling again right away
+        match poll(&mut fds, left.as_micros().div_ceil(1000) as i32) {
+            Ok(0) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => return Ok(Wait::Timeout),
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => return Ok(Wait::Readable),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Wait::Shutdown)
+}
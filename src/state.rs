@@ -0,0 +1,413 @@
+use evdev::{
+    InputEvent,
+    EventType,
+};
+use std::{
+    collections::HashMap,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use crate::config::SlotConflict;
+
+// one device's events up to its SYN_REPORT
+#[derive(Debug)]
+pub struct Frame {
+    // the device it came from, which stays the same when one comes back on another node
+    pub source: u64,
+    // rank among devices disagreeing about the dpad, lower wins
+    pub priority: usize,
+    // virtual pad it goes to
+    pub pad: usize,
+    pub events: Vec<InputEvent>,
+}
+
+// a device's end of the channel to main, tagging every frame with where it came from
+#[derive(Clone)]
+pub struct FrameSender {
+    tx: mpsc::Sender<Frame>,
+    source: u64,
+    priority: usize,
+    pad: usize,
+}
+
+impl FrameSender {
+    pub fn new(tx: mpsc::Sender<Frame>, source: u64, priority: usize) -> FrameSender {
+        FrameSender {
+            tx,
+            source,
+            priority,
+            pad: 0,
+        }
+    }
+
+    pub fn for_pad(self, pad: usize) -> FrameSender {
+        FrameSender { pad, ..self }
+    }
+
+    pub fn source(&self) -> u64 {
+        self.source
+    }
+
+    // for a device that came back, so whatever it held before is its own again
+    pub fn resuming(self, source: u64) -> FrameSender {
+        FrameSender { source, ..self }
+    }
+
+    pub fn send(&self, events: Vec<InputEvent>) -> Result<(), mpsc::SendError<Frame>> {
+        self.tx.send(Frame {
+            source: self.source,
+            priority: self.priority,
+            pad: self.pad,
+            events,
+        })
+    }
+}
+
+// every key and axis we sent out that isn't at rest, so it can be released later on
+#[derive(Default, Debug)]
+pub struct HeldState {
+    values: HashMap<(u16, u16), i32>,
+}
+
+impl HeldState {
+    pub fn update(&mut self, ev: &InputEvent) {
+        let id = (ev.event_type().0, ev.code());
+        if ev.value() == 0 {
+            self.values.remove(&id);
+        } else {
+            self.values.insert(id, ev.value());
+        }
+    }
+
+    // rest is 0 for everything the virtual pad has, including triggers
+    pub fn neutral_frame(&self) -> Vec<InputEvent> {
+        self.values.keys()
+            .map(|(type_, code)| InputEvent::new(EventType(*type_), *code, 0))
+            .collect()
+    }
+
+    // everything as it is right now, for bringing a fresh device up to date
+    pub fn current_frame(&self) -> Vec<InputEvent> {
+        self.values.iter()
+            .map(|((type_, code), value)| InputEvent::new(EventType(*type_), *code, *value))
+            .collect()
+    }
+
+    // the neutral frame, forgetting everything it releases
+    pub fn reset(&mut self) -> Vec<InputEvent> {
+        let frame = self.neutral_frame();
+        self.values.clear();
+        frame
+    }
+}
+
+// a device's frame as it comes in, split across fetches maybe, until its SYN_REPORT is in
+// or, for devices that never send one, until it's been pending for too long
+pub struct PendingFrame {
+    events: Vec<InputEvent>,
+    since: Instant,
+    deadline: Duration,
+}
+
+impl PendingFrame {
+    pub fn new(deadline: Duration) -> PendingFrame {
+        PendingFrame {
+            events: Vec::new(),
+            since: Instant::now(),
+            deadline,
+        }
+    }
+
+    pub fn push(&mut self, ev: InputEvent, now: Instant) {
+        if self.events.is_empty() {
+            self.since = now;
+        }
+        self.events.push(ev);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn take(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        match self.events.is_empty() {
+            true => None,
+            false => Some((self.since + self.deadline).saturating_duration_since(now)),
+        }
+    }
+
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        self.due_in(now) == Some(Duration::ZERO)
+    }
+}
+
+// events per second, sampled over fixed intervals
+pub struct RateCounter {
+    interval: Duration,
+    since: Instant,
+    count: u64,
+}
+
+impl RateCounter {
+    pub fn new(interval: Duration) -> RateCounter {
+        RateCounter {
+            interval,
+            since: Instant::now(),
+            count: 0,
+        }
+    }
+
+    // the rate of the interval that just ended, if one did
+    pub fn tick(&mut self, events: u64, now: Instant) -> Option<f64> {
+        self.count += events;
+        let elapsed = now.duration_since(self.since);
+        if elapsed < self.interval {
+            return None;
+        }
+
+        let rate = self.count as f64 / elapsed.as_secs_f64();
+        self.count = 0;
+        self.since = now;
+        Some(rate)
+    }
+}
+
+struct Slot {
+    identity: String,
+    owner: u64,
+    // its device went away and may come back for it
+    lingering: bool,
+}
+
+// which handler holds a slot, if any
+pub struct Claim {
+    pub pad: usize,
+    owner: Option<u64>,
+}
+
+// player numbers, one per virtual pad. a device coming back within its grace period
+// takes over the slot it had, one pinned to a player gets that one, everyone else gets
+// the lowest free one
+pub struct Slots {
+    slots: Vec<Option<Slot>>,
+    next_owner: u64,
+    conflict: SlotConflict,
+}
+
+impl Slots {
+    pub fn new(count: usize, conflict: SlotConflict) -> Slots {
+        Slots {
+            slots: (0..count.max(1)).map(|_| None).collect(),
+            next_owner: 0,
+            conflict,
+        }
+    }
+
+    // once all are taken, the rest share the first pad. None if the device is pinned to a
+    // taken slot and conflicts are rejected
+    pub fn claim(&mut self, identity: &str, pinned: Option<usize>) -> Option<Claim> {
+        let returning = self.slots.iter()
+            .position(|slot| slot.as_ref().is_some_and(|slot| slot.lingering && slot.identity == identity));
+        let pad = match pinned.filter(|pad| *pad < self.slots.len()) {
+            // its own lingering slot is still fine
+            Some(pad) if self.slots[pad].is_some() && returning != Some(pad) => return match self.conflict {
+                SlotConflict::Merge => Some(Claim { pad, owner: None }),
+                SlotConflict::Reject => None,
+            },
+            Some(pad) => pad,
+            None => match returning.or_else(|| self.slots.iter().position(Option::is_none)) {
+                Some(pad) => pad,
+                None => return Some(Claim { pad: 0, owner: None }),
+            },
+        };
+
+        self.next_owner += 1;
+        self.slots[pad] = Some(Slot {
+            identity: identity.to_string(),
+            owner: self.next_owner,
+            lingering: false,
+        });
+        Some(Claim { pad, owner: Some(self.next_owner) })
+    }
+
+    pub fn linger(&mut self, claim: &Claim) {
+        if let Some(slot) = self.owned(claim) {
+            slot.lingering = true;
+        }
+    }
+
+    // does nothing if the slot was taken over meanwhile
+    pub fn release(&mut self, claim: &Claim) {
+        if self.owned(claim).is_some() {
+            self.slots[claim.pad] = None;
+        }
+    }
+
+    // pads someone plays on, or is about to come back to
+    pub fn claimed(&self) -> Vec<usize> {
+        self.slots.iter()
+            .enumerate()
+            .filter_map(|(pad, slot)| slot.as_ref().map(|_| pad))
+            .collect()
+    }
+
+    fn owned(&mut self, claim: &Claim) -> Option<&mut Slot> {
+        self.slots[claim.pad].as_mut().filter(|slot| Some(slot.owner) == claim.owner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_over_an_interval() {
+        let mut counter = RateCounter::new(Duration::from_secs(2));
+        let start = counter.since;
+        assert_eq!(counter.tick(100, start + Duration::from_secs(1)), None);
+        assert_eq!(counter.tick(300, start + Duration::from_secs(2)), Some(200.0));
+        // and it starts counting over
+        assert_eq!(counter.tick(50, start + Duration::from_secs(3)), None);
+        assert_eq!(counter.tick(50, start + Duration::from_secs(4)), Some(50.0));
+    }
+
+    #[test]
+    fn partial_frames_go_out_after_the_deadline() {
+        let mut frame = PendingFrame::new(Duration::from_millis(20));
+        let start = Instant::now();
+        assert_eq!(frame.due_in(start), None);
+        assert!(!frame.is_overdue(start));
+
+        frame.push(InputEvent::new(EventType::KEY, 0x130, 1), start);
+        frame.push(InputEvent::new(EventType::KEY, 0x131, 1), start + Duration::from_millis(15));
+        // the deadline counts from the first event
+        assert_eq!(frame.due_in(start + Duration::from_millis(15)), Some(Duration::from_millis(5)));
+        assert!(!frame.is_overdue(start + Duration::from_millis(19)));
+        assert!(frame.is_overdue(start + Duration::from_millis(20)));
+        assert_eq!(frame.take().len(), 2);
+        assert!(frame.is_empty());
+        assert!(!frame.is_overdue(start + Duration::from_millis(100)));
+
+        frame.push(InputEvent::new(EventType::KEY, 0x130, 0), start + Duration::from_millis(100));
+        assert_eq!(frame.due_in(start + Duration::from_millis(100)), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn held_inputs_are_replayed() {
+        let mut held = HeldState::default();
+        held.update(&InputEvent::new(EventType::KEY, 0x130, 1));
+        held.update(&InputEvent::new(EventType::KEY, 0x131, 1));
+        held.update(&InputEvent::new(EventType::KEY, 0x131, 0));
+        held.update(&InputEvent::new(EventType::ABSOLUTE, 0x02, 200));
+
+        let mut replay: Vec<(u16, u16, i32)> = held.current_frame().iter()
+            .map(|ev| (ev.event_type().0, ev.code(), ev.value()))
+            .collect();
+        replay.sort();
+        assert_eq!(replay, [(EventType::KEY.0, 0x130, 1), (EventType::ABSOLUTE.0, 0x02, 200)]);
+        // replaying doesn't forget anything, a second rebuild gets the same
+        assert_eq!(held.current_frame().len(), 2);
+        held.reset();
+        assert!(held.current_frame().is_empty());
+    }
+
+    #[test]
+    fn resumed_devices_send_as_before() {
+        let (tx, rx) = mpsc::channel();
+        let tx = FrameSender::new(tx, 9, 2).for_pad(1).resuming(4);
+        tx.send(vec![InputEvent::new(EventType::KEY, 0x130, 0)]).unwrap();
+        let frame = rx.try_recv().unwrap();
+        assert_eq!((frame.source, frame.priority, frame.pad), (4, 2, 1));
+    }
+
+    #[test]
+    fn players_get_the_lowest_free_slot() {
+        let mut slots = Slots::new(3, SlotConflict::Merge);
+        let first = slots.claim("a", None).unwrap();
+        let second = slots.claim("b", None).unwrap();
+        assert_eq!((first.pad, second.pad), (0, 1));
+        assert_eq!(slots.claimed(), [0, 1]);
+        slots.release(&first);
+        assert_eq!(slots.claimed(), [1]);
+        assert_eq!(slots.claim("c", None).unwrap().pad, 0);
+        assert_eq!(slots.claim("d", None).unwrap().pad, 2);
+        // everyone past the last slot shares the first pad, and gives nothing back
+        let extra = slots.claim("e", None).unwrap();
+        assert_eq!(extra.pad, 0);
+        slots.release(&extra);
+        assert_eq!(slots.claimed(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn returning_devices_get_their_slot_back() {
+        let mut slots = Slots::new(2, SlotConflict::Merge);
+        let gone = slots.claim("a", None).unwrap();
+        slots.claim("b", None).unwrap();
+        slots.linger(&gone);
+        // still held for it meanwhile
+        assert_eq!(slots.claimed(), [0, 1]);
+        let back = slots.claim("a", None).unwrap();
+        assert_eq!(back.pad, 0);
+        // the old handler letting go late doesn't take it from the new one
+        slots.release(&gone);
+        assert_eq!(slots.claimed(), [0, 1]);
+        slots.release(&back);
+        assert_eq!(slots.claimed(), [1]);
+    }
+
+    #[test]
+    fn lingering_slots_are_for_their_device_only() {
+        let mut slots = Slots::new(2, SlotConflict::Merge);
+        let gone = slots.claim("a", None).unwrap();
+        slots.linger(&gone);
+        assert_eq!(slots.claim("b", None).unwrap().pad, 1);
+        // no slot left, so it shares the first pad without owning it
+        let shared = slots.claim("c", None).unwrap();
+        assert_eq!(shared.pad, 0);
+        slots.release(&gone);
+        assert_eq!(slots.claimed(), [1]);
+    }
+
+    #[test]
+    fn pinned_devices_get_their_player() {
+        let mut slots = Slots::new(3, SlotConflict::Merge);
+        assert_eq!(slots.claim("a", None).unwrap().pad, 0);
+        assert_eq!(slots.claim("b", Some(2)).unwrap().pad, 2);
+        // the lowest free slot is still the lowest free slot
+        assert_eq!(slots.claim("c", None).unwrap().pad, 1);
+        // and pins past the last pad are ignored, which leaves the overflow
+        assert_eq!(slots.claim("d", Some(5)).unwrap().pad, 0);
+    }
+
+    #[test]
+    fn pinned_conflicts_merge_into_the_slot() {
+        let mut slots = Slots::new(2, SlotConflict::Merge);
+        let first = slots.claim("a", Some(1)).unwrap();
+        let second = slots.claim("b", Some(1)).unwrap();
+        assert_eq!((first.pad, second.pad), (1, 1));
+        // the second doesn't own it, so letting go gives nothing back
+        slots.release(&second);
+        assert_eq!(slots.claimed(), [1]);
+        slots.release(&first);
+        assert!(slots.claimed().is_empty());
+    }
+
+    #[test]
+    fn pinned_conflicts_can_be_rejected() {
+        let mut slots = Slots::new(2, SlotConflict::Reject);
+        let gone = slots.claim("a", Some(1)).unwrap();
+        assert!(slots.claim("b", Some(1)).is_none());
+        // a lingering slot is still taken for anyone else, but not for its own device
+        slots.linger(&gone);
+        assert!(slots.claim("b", Some(1)).is_none());
+        assert_eq!(slots.claim("a", Some(1)).unwrap().pad, 1);
+    }
+}
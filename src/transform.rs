@@ -0,0 +1,762 @@
+use evdev::{
+    AbsoluteAxisType,
+    InputEventKind,
+    InputEvent,
+    EventType,
+    Key,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use libdogd::log_info;
+use crate::{
+    config::{Config, DeviceConfig, MixConfig, SmoothingConfig, TwoStageConfig},
+    remap::{self, Code},
+};
+
+fn parse_axis(name: &str) -> Option<AbsoluteAxisType> {
+    match remap::parse_code(name) {
+        Some(Code::Abs(axis)) => Some(axis),
+        _ => {
+            log_info(format!("Ignoring unknown axis {}", name));
+            None
+        },
+    }
+}
+
+// axes by name, e.g. for the raw_axes option
+pub fn parse_axes(names: &[String]) -> HashSet<u16> {
+    names.iter()
+        .filter_map(|name| parse_axis(name))
+        .map(|axis| axis.0)
+        .collect()
+}
+
+struct Mix {
+    output: AbsoluteAxisType,
+    inputs: Vec<(u16, f32)>,
+}
+
+// output axes defined as a weighted sum of (already scaled) source axes
+pub struct Mixer {
+    mixes: Vec<Mix>,
+    values: HashMap<u16, i32>,
+}
+
+impl Mixer {
+    pub fn new(mixes: &[MixConfig]) -> Mixer {
+        let mixes = mixes.iter()
+            .filter_map(|mix| Some(Mix {
+                output: parse_axis(&mix.output)?,
+                inputs: mix.inputs.iter()
+                    .map(|input| Some((parse_axis(&input.axis)?.0, input.weight)))
+                    .collect::<Option<Vec<_>>>()?,
+            }))
+            .collect();
+
+        Mixer {
+            mixes,
+            values: HashMap::new(),
+        }
+    }
+
+    // axes that are the output of a mix only ever report the mixed value
+    pub fn apply(&mut self, ev: InputEvent, config: &Config) -> Vec<InputEvent> {
+        let InputEventKind::AbsAxis(axis) = ev.kind() else { return vec![ev] };
+        self.values.insert(axis.0, ev.value());
+
+        let mut out = Vec::new();
+        if !self.mixes.iter().any(|mix| mix.output == axis) {
+            out.push(ev);
+        }
+
+        for mix in self.mixes.iter().filter(|mix| mix.inputs.iter().any(|(code, _)| *code == axis.0)) {
+            let sum: f32 = mix.inputs.iter()
+                .map(|(code, weight)| *self.values.get(code).unwrap_or(&0) as f32 * weight)
+                .sum();
+            let (out_min, out_max) = crate::output_range(mix.output, config);
+            out.push(InputEvent::new(EventType::ABSOLUTE, mix.output.0, (sum.round() as i32).clamp(out_min, out_max)));
+        }
+        out
+    }
+}
+
+// tapping a sticky key latches it down until it's tapped again
+pub struct Sticky {
+    keys: HashSet<u16>,
+    latched: HashSet<u16>,
+}
+
+impl Sticky {
+    pub fn new(names: &[String]) -> Sticky {
+        let keys = names.iter()
+            .filter_map(|name| {
+                let key = remap::parse_key_alias(name);
+                if key.is_none() {
+                    log_info(format!("Ignoring unknown key {} in sticky", name));
+                }
+                key
+            })
+            .map(|key| key.0)
+            .collect();
+
+        Sticky {
+            keys,
+            latched: HashSet::new(),
+        }
+    }
+
+    // once everything's been released there's nothing latched anymore either
+    pub fn reset(&mut self) {
+        self.latched.clear();
+    }
+
+    pub fn apply(&mut self, ev: InputEvent) -> Option<InputEvent> {
+        let InputEventKind::Key(key) = ev.kind() else { return Some(ev) };
+        if !self.keys.contains(&key.0) {
+            return Some(ev);
+        }
+        // physical releases don't matter, only taps do
+        if ev.value() == 0 {
+            return None;
+        }
+
+        let latched = !self.latched.remove(&key.0);
+        if latched {
+            self.latched.insert(key.0);
+        }
+        Some(InputEvent::new(EventType::KEY, key.0, latched as i32))
+    }
+}
+
+// remaps keys while a modifier is held, which may well be on some other device
+pub struct Layer {
+    map: HashMap<u16, Key>,
+    // where presses went, so releases follow them even if the modifier is gone by then
+    pressed: HashMap<u16, Key>,
+}
+
+impl Layer {
+    pub fn new(remap: &HashMap<String, String>) -> Layer {
+        let map = remap.iter()
+            .filter_map(|(from, to)| match (remap::parse_key_alias(from), remap::parse_key_alias(to)) {
+                (Some(from), Some(to)) => Some((from.0, to)),
+                _ => {
+                    log_info(format!("Ignoring modifier layer mapping {} -> {}", from, to));
+                    None
+                },
+            })
+            .collect();
+
+        Layer {
+            map,
+            pressed: HashMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pressed.clear();
+    }
+
+    pub fn apply(&mut self, ev: InputEvent, active: bool) -> InputEvent {
+        let InputEventKind::Key(key) = ev.kind() else { return ev };
+        let target = if ev.value() == 0 {
+            self.pressed.remove(&key.0)
+        } else if active {
+            self.map.get(&key.0).copied()
+        } else {
+            None
+        };
+
+        match target {
+            Some(to) => {
+                if ev.value() != 0 {
+                    self.pressed.insert(key.0, to);
+                }
+                InputEvent::new(EventType::KEY, to.0, ev.value())
+            },
+            None => ev,
+        }
+    }
+}
+
+// extra buttons for a soft and a full pull of each trigger, for games that tell the two apart
+pub struct TwoStage {
+    soft: i32,
+    full: i32,
+    pressed: HashSet<u16>,
+}
+
+impl TwoStage {
+    pub const LEFT: (Key, Key) = (Key::BTN_TRIGGER_HAPPY1, Key::BTN_TRIGGER_HAPPY2);
+    pub const RIGHT: (Key, Key) = (Key::BTN_TRIGGER_HAPPY3, Key::BTN_TRIGGER_HAPPY4);
+
+    pub fn new(config: &TwoStageConfig) -> TwoStage {
+        let at = |fraction: f32| (fraction.clamp(0.0, 1.0) * crate::max_out_trig() as f32).round().max(1.0) as i32;
+        TwoStage {
+            soft: at(config.soft),
+            full: at(config.full),
+            pressed: HashSet::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pressed.clear();
+    }
+
+    // button changes caused by an already scaled trigger event
+    pub fn apply(&mut self, ev: &InputEvent) -> Vec<InputEvent> {
+        let (soft, full) = match ev.kind() {
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Z) => TwoStage::LEFT,
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_RZ) => TwoStage::RIGHT,
+            _ => return Vec::new(),
+        };
+
+        [(soft, self.soft), (full, self.full)].into_iter()
+            .filter_map(|(key, threshold)| {
+                let pressed = ev.value() >= threshold;
+                if pressed == self.pressed.contains(&key.0) {
+                    return None;
+                }
+                if pressed {
+                    self.pressed.insert(key.0);
+                } else {
+                    self.pressed.remove(&key.0);
+                }
+                Some(InputEvent::new(EventType::KEY, key.0, pressed as i32))
+            })
+            .collect()
+    }
+}
+
+// how often an axis that jumped moves on toward where it went, about a frame at 100Hz
+const SMOOTHING_STEP: Duration = Duration::from_millis(10);
+
+// what the next step of a jump from `last` toward `target` lands on, never stalling short of it
+fn smoothing_step(last: i32, target: i32, threshold: i32, factor: f32) -> i32 {
+    let delta = target - last;
+    if delta.abs() <= threshold {
+        return target;
+    }
+    match (delta as f32 * factor).round() as i32 {
+        0 => last + delta.signum(),
+        step => last + step,
+    }
+}
+
+// eases big jumps on the configured axes while leaving slow movement alone,
+// a flick lands over the following steps instead of all at once, held or not
+pub struct Smoothing {
+    // threshold and the fraction of a big jump let through per step, by axis
+    axes: HashMap<u16, (i32, f32)>,
+    last: HashMap<u16, i32>,
+    // where the axes that haven't caught up yet are headed
+    targets: HashMap<u16, i32>,
+    next_step: Option<Instant>,
+}
+
+impl Smoothing {
+    pub fn new(config: &[SmoothingConfig]) -> Smoothing {
+        let axes = config.iter()
+            .filter_map(|smooth| Some((parse_axis(&smooth.axis)?.0, (smooth.threshold, smooth.factor.clamp(0.0, 1.0)))))
+            .collect();
+
+        Smoothing {
+            axes,
+            last: HashMap::new(),
+            targets: HashMap::new(),
+            next_step: None,
+        }
+    }
+
+    pub fn apply(&mut self, ev: InputEvent, now: Instant) -> InputEvent {
+        let InputEventKind::AbsAxis(axis) = ev.kind() else { return ev };
+        let Some((threshold, factor)) = self.axes.get(&axis.0).copied() else { return ev };
+
+        let value = match self.last.get(&axis.0) {
+            Some(&last) => smoothing_step(last, ev.value(), threshold, factor),
+            None => ev.value(),
+        };
+        self.last.insert(axis.0, value);
+        if value == ev.value() {
+            self.targets.remove(&axis.0);
+            if self.targets.is_empty() {
+                self.next_step = None;
+            }
+        } else {
+            self.targets.insert(axis.0, ev.value());
+            self.next_step.get_or_insert(now + SMOOTHING_STEP);
+        }
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        self.next_step.map(|at| at.saturating_duration_since(now))
+    }
+
+    // the next step of every axis still catching up, if it's time
+    pub fn poll(&mut self, now: Instant) -> Vec<InputEvent> {
+        if self.next_step.is_none_or(|at| at > now) {
+            return Vec::new();
+        }
+        let mut out = Vec::with_capacity(self.targets.len());
+        for (&axis, &target) in self.targets.iter() {
+            let (threshold, factor) = self.axes[&axis];
+            let value = smoothing_step(self.last[&axis], target, threshold, factor);
+            self.last.insert(axis, value);
+            out.push(InputEvent::new(EventType::ABSOLUTE, axis, value));
+        }
+        self.targets.retain(|axis, target| self.last[axis] != *target);
+        self.next_step = (!self.targets.is_empty()).then(|| now + SMOOTHING_STEP);
+        out
+    }
+
+    pub fn reset(&mut self) {
+        self.last.clear();
+        self.targets.clear();
+        self.next_step = None;
+    }
+}
+
+// per device fixes for sticks and triggers that are wired up wrong, on values already scaled to ours
+pub struct AxisFix {
+    inverted: HashSet<u16>,
+    swap_sticks: bool,
+}
+
+impl AxisFix {
+    pub fn new(device: Option<&DeviceConfig>) -> AxisFix {
+        AxisFix {
+            inverted: device.map(|device| parse_axes(&device.invert)).unwrap_or_default(),
+            swap_sticks: device.is_some_and(|device| device.swap_sticks),
+        }
+    }
+
+    // axes are named as they come out of remaps, so inverting comes before swapping
+    pub fn apply(&self, ev: InputEvent, config: &Config) -> InputEvent {
+        let InputEventKind::AbsAxis(axis) = ev.kind() else {
+            return ev;
+        };
+        let value = match self.inverted.contains(&axis.0) {
+            true => invert_axis(axis, ev.value(), config),
+            false => ev.value(),
+        };
+        let axis = match (self.swap_sticks, axis) {
+            (true, AbsoluteAxisType::ABS_X) => AbsoluteAxisType::ABS_RX,
+            (true, AbsoluteAxisType::ABS_Y) => AbsoluteAxisType::ABS_RY,
+            (true, AbsoluteAxisType::ABS_RX) => AbsoluteAxisType::ABS_X,
+            (true, AbsoluteAxisType::ABS_RY) => AbsoluteAxisType::ABS_Y,
+            (_, axis) => axis,
+        };
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+}
+
+// triggers flip end to end, sticks and hats around their center so it stays put
+fn invert_axis(axis: AbsoluteAxisType, value: i32, config: &Config) -> i32 {
+    let (min, max) = crate::output_range(axis, config);
+    if crate::is_trigger(axis) {
+        min + max - value
+    } else {
+        (-value).clamp(min, max)
+    }
+}
+
+// for devices mounted upside down: sticks and dpad point the other way, left and right swap places
+pub fn flip(ev: InputEvent, config: &Config) -> InputEvent {
+    let swapped = |a: u16, b: u16, code: u16| if code == a { b } else if code == b { a } else { code };
+    match ev.kind() {
+        InputEventKind::AbsAxis(axis) => match axis {
+            AbsoluteAxisType::ABS_X | AbsoluteAxisType::ABS_Y | AbsoluteAxisType::ABS_RX | AbsoluteAxisType::ABS_RY
+            | AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y => {
+                InputEvent::new(EventType::ABSOLUTE, axis.0, invert_axis(axis, ev.value(), config))
+            },
+            _ => InputEvent::new(EventType::ABSOLUTE, swapped(AbsoluteAxisType::ABS_Z.0, AbsoluteAxisType::ABS_RZ.0, axis.0), ev.value()),
+        },
+        InputEventKind::Key(key) => {
+            let code = [(Key::BTN_TL, Key::BTN_TR), (Key::BTN_TL2, Key::BTN_TR2), (Key::BTN_THUMBL, Key::BTN_THUMBR),
+                        (Key::BTN_DPAD_UP, Key::BTN_DPAD_DOWN), (Key::BTN_DPAD_LEFT, Key::BTN_DPAD_RIGHT)]
+                .into_iter()
+                .fold(key.0, |code, (a, b)| swapped(a.0, b.0, code));
+            InputEvent::new(EventType::KEY, code, ev.value())
+        },
+        _ => ev,
+    }
+}
+
+// while the modifier is held the dpad drives the right stick instead, for camera control on
+// handhelds without one. a tap of the modifier on its own still goes out as itself
+pub struct DpadStick {
+    modifier: Key,
+    held: bool,
+    used: bool,
+    // hat axes that are pressed, and whether that press went to the stick, so releases follow
+    // them even if the modifier changed meanwhile
+    pressed: HashMap<u16, bool>,
+}
+
+impl DpadStick {
+    pub fn new(modifier: Key) -> DpadStick {
+        DpadStick {
+            modifier,
+            held: false,
+            used: false,
+            pressed: HashMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.held = false;
+        self.pressed.clear();
+    }
+
+    fn stick(hat: u16) -> u16 {
+        match hat == AbsoluteAxisType::ABS_HAT0X.0 {
+            true => AbsoluteAxisType::ABS_RX.0,
+            false => AbsoluteAxisType::ABS_RY.0,
+        }
+    }
+
+    pub fn apply(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        match ev.kind() {
+            InputEventKind::Key(key) if key == self.modifier => {
+                self.held = ev.value() != 0;
+                if self.held {
+                    self.used = false;
+                    return Vec::new();
+                }
+                if !self.used {
+                    return vec![InputEvent::new(EventType::KEY, key.0, 1), InputEvent::new(EventType::KEY, key.0, 0)];
+                }
+                // a stick still deflected would stay that way, its hat going back to being a hat
+                let mut recentered = Vec::new();
+                self.pressed.retain(|hat, to_stick| {
+                    if *to_stick {
+                        recentered.push(InputEvent::new(EventType::ABSOLUTE, Self::stick(*hat), 0));
+                    }
+                    !*to_stick
+                });
+                recentered
+            },
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_HAT0X | AbsoluteAxisType::ABS_HAT0Y) => {
+                let to_stick = match ev.value() {
+                    0 => self.pressed.remove(&ev.code()).unwrap_or(false),
+                    _ => *self.pressed.entry(ev.code()).or_insert(self.held),
+                };
+                if !to_stick {
+                    return vec![ev];
+                }
+                self.used = true;
+                vec![InputEvent::new(EventType::ABSOLUTE, Self::stick(ev.code()), ev.value().signum() * crate::MAX_OUT_ANALOG)]
+            },
+            _ => vec![ev],
+        }
+    }
+}
+
+const STICKS: [(AbsoluteAxisType, AbsoluteAxisType); 2] = [
+    (AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y),
+    (AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY),
+];
+
+// radial deadzone over both axes of a stick, what's left outside of it is stretched back over
+// the full range so there's no jump at the edge
+pub struct Deadzone {
+    radius: f64,
+    // latest scaled values, by axis
+    values: HashMap<u16, i32>,
+    sticks: Vec<(AbsoluteAxisType, AbsoluteAxisType)>,
+}
+
+impl Deadzone {
+    // radius as a fraction of full deflection, sticks with a raw axis are left alone
+    pub fn new(radius: f32, raw_axes: &HashSet<u16>) -> Deadzone {
+        Deadzone {
+            radius: radius.clamp(0.0, 0.99) as f64,
+            values: HashMap::new(),
+            sticks: STICKS.into_iter()
+                .filter(|(x, y)| !raw_axes.contains(&x.0) && !raw_axes.contains(&y.0))
+                .collect(),
+        }
+    }
+
+    // both axes of the stick whenever either of them moves
+    pub fn apply(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        let InputEventKind::AbsAxis(axis) = ev.kind() else { return vec![ev] };
+        let Some((x, y)) = self.sticks.iter().copied().find(|(x, y)| *x == axis || *y == axis) else { return vec![ev] };
+        if self.radius == 0.0 {
+            return vec![ev];
+        }
+        self.values.insert(axis.0, ev.value());
+
+        let full = crate::MAX_OUT_ANALOG as f64;
+        let (vx, vy) = (self.values.get(&x.0).copied().unwrap_or(0), self.values.get(&y.0).copied().unwrap_or(0));
+        let magnitude = (vx as f64).hypot(vy as f64) / full;
+        let scale = if magnitude < self.radius {
+            0.0
+        } else {
+            ((magnitude - self.radius) / (1.0 - self.radius)).min(1.0) / magnitude
+        };
+
+        let (min, max) = (crate::MIN_OUT_ANALOG, crate::MAX_OUT_ANALOG);
+        [(x, vx), (y, vy)].into_iter()
+            .map(|(axis, value)| InputEvent::new(EventType::ABSOLUTE, axis.0, ((value as f64 * scale).round() as i32).clamp(min, max)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MixInput;
+
+    fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.0, value)
+    }
+
+    fn values(events: &[InputEvent]) -> Vec<(u16, i32)> {
+        events.iter().map(|ev| (ev.code(), ev.value())).collect()
+    }
+
+    fn averaged_sticks() -> Mixer {
+        Mixer::new(&[MixConfig {
+            output: "ABS_X".into(),
+            inputs: vec![MixInput { axis: "ABS_X".into(), weight: 0.5 }, MixInput { axis: "ABS_RX".into(), weight: 0.5 }],
+        }])
+    }
+
+    #[test]
+    fn mix_replaces_its_output() {
+        let config = Config::default();
+        let mut mixer = averaged_sticks();
+        assert_eq!(values(&mixer.apply(abs(AbsoluteAxisType::ABS_X, 1000), &config)), [(AbsoluteAxisType::ABS_X.0, 500)]);
+        assert_eq!(values(&mixer.apply(abs(AbsoluteAxisType::ABS_RX, 3000), &config)),
+            [(AbsoluteAxisType::ABS_RX.0, 3000), (AbsoluteAxisType::ABS_X.0, 2000)]);
+        assert_eq!(values(&mixer.apply(abs(AbsoluteAxisType::ABS_Y, 42), &config)), [(AbsoluteAxisType::ABS_Y.0, 42)]);
+        assert_eq!(values(&mixer.apply(key(Key::BTN_SOUTH, 1), &config)), [(Key::BTN_SOUTH.0, 1)]);
+    }
+
+    #[test]
+    fn mix_stays_in_range() {
+        let config = Config::default();
+        let mut mixer = Mixer::new(&[MixConfig {
+            output: "ABS_Y".into(),
+            inputs: vec![MixInput { axis: "ABS_Y".into(), weight: 1.0 }, MixInput { axis: "ABS_RY".into(), weight: 1.0 }],
+        }]);
+        mixer.apply(abs(AbsoluteAxisType::ABS_Y, -32768), &config);
+        assert_eq!(values(&mixer.apply(abs(AbsoluteAxisType::ABS_RY, -32768), &config))[1], (AbsoluteAxisType::ABS_Y.0, -32768));
+    }
+
+    #[test]
+    fn sticky_keys_latch_on_taps() {
+        let mut sticky = Sticky::new(&["a".to_string()]);
+        let out = |sticky: &mut Sticky, ev| sticky.apply(ev).map(|ev: InputEvent| (ev.code(), ev.value()));
+        assert_eq!(out(&mut sticky, key(Key::BTN_SOUTH, 1)), Some((Key::BTN_SOUTH.0, 1)));
+        assert_eq!(out(&mut sticky, key(Key::BTN_SOUTH, 0)), None);
+        assert_eq!(out(&mut sticky, key(Key::BTN_SOUTH, 1)), Some((Key::BTN_SOUTH.0, 0)));
+        assert_eq!(out(&mut sticky, key(Key::BTN_SOUTH, 0)), None);
+        assert_eq!(out(&mut sticky, key(Key::BTN_EAST, 1)), Some((Key::BTN_EAST.0, 1)));
+        assert_eq!(out(&mut sticky, key(Key::BTN_EAST, 0)), Some((Key::BTN_EAST.0, 0)));
+
+        out(&mut sticky, key(Key::BTN_SOUTH, 1));
+        sticky.reset();
+        assert_eq!(out(&mut sticky, key(Key::BTN_SOUTH, 1)), Some((Key::BTN_SOUTH.0, 1)));
+    }
+
+    #[test]
+    fn layer_follows_the_modifier() {
+        let mut layer = Layer::new(&[("a".to_string(), "x".to_string())].into());
+        let out = |layer: &mut Layer, ev, active| {
+            let ev: InputEvent = layer.apply(ev, active);
+            (ev.code(), ev.value())
+        };
+        assert_eq!(out(&mut layer, key(Key::BTN_SOUTH, 1), false), (Key::BTN_SOUTH.0, 1));
+        assert_eq!(out(&mut layer, key(Key::BTN_SOUTH, 0), true), (Key::BTN_SOUTH.0, 0));
+        assert_eq!(out(&mut layer, key(Key::BTN_SOUTH, 1), true), (Key::BTN_NORTH.0, 1));
+        // the release follows the press, modifier or not
+        assert_eq!(out(&mut layer, key(Key::BTN_SOUTH, 0), false), (Key::BTN_NORTH.0, 0));
+        assert_eq!(out(&mut layer, key(Key::BTN_EAST, 1), true), (Key::BTN_EAST.0, 1));
+    }
+
+    #[test]
+    fn two_stage_buttons_follow_the_pull() {
+        let mut stages = TwoStage::new(&TwoStageConfig { soft: 0.5, full: 1.0 });
+        let (soft, full) = TwoStage::LEFT;
+        let max = crate::max_out_trig();
+        assert!(stages.apply(&abs(AbsoluteAxisType::ABS_Z, max / 4)).is_empty());
+        assert_eq!(values(&stages.apply(&abs(AbsoluteAxisType::ABS_Z, max))), [(soft.0, 1), (full.0, 1)]);
+        assert!(stages.apply(&abs(AbsoluteAxisType::ABS_Z, max)).is_empty());
+        assert_eq!(values(&stages.apply(&abs(AbsoluteAxisType::ABS_Z, max * 3 / 4))), [(full.0, 0)]);
+        assert_eq!(values(&stages.apply(&abs(AbsoluteAxisType::ABS_Z, 0))), [(soft.0, 0)]);
+        assert!(stages.apply(&abs(AbsoluteAxisType::ABS_X, max)).is_empty());
+    }
+
+    #[test]
+    fn two_stage_triggers_are_independent() {
+        let mut stages = TwoStage::new(&TwoStageConfig { soft: 0.5, full: 1.0 });
+        let max = crate::max_out_trig();
+        assert_eq!(values(&stages.apply(&abs(AbsoluteAxisType::ABS_RZ, max))), [(TwoStage::RIGHT.0.0, 1), (TwoStage::RIGHT.1.0, 1)]);
+        assert!(stages.apply(&abs(AbsoluteAxisType::ABS_Z, 0)).is_empty());
+        stages.reset();
+        assert_eq!(values(&stages.apply(&abs(AbsoluteAxisType::ABS_RZ, max))), [(TwoStage::RIGHT.0.0, 1), (TwoStage::RIGHT.1.0, 1)]);
+    }
+
+    #[test]
+    fn small_moves_pass_and_flicks_converge() {
+        let mut smoothing = Smoothing::new(&[SmoothingConfig { axis: "ABS_X".into(), threshold: 1000, factor: 0.5 }]);
+        let now = Instant::now();
+        assert_eq!(smoothing.apply(abs(AbsoluteAxisType::ABS_X, 0), now).value(), 0);
+        assert_eq!(smoothing.apply(abs(AbsoluteAxisType::ABS_X, 800), now).value(), 800);
+        assert_eq!(smoothing.due_in(now), None);
+        // other axes aren't touched at all
+        assert_eq!(smoothing.apply(abs(AbsoluteAxisType::ABS_Y, 30000), now).value(), 30000);
+
+        // flicked and held there, no more events come in
+        assert_eq!(smoothing.apply(abs(AbsoluteAxisType::ABS_X, 32000), now).value(), 16400);
+        assert!(smoothing.poll(now).is_empty());
+        let mut at = now;
+        let mut last = 16400;
+        while let Some(due) = smoothing.due_in(at) {
+            at += due;
+            let stepped = smoothing.poll(at);
+            assert_eq!(stepped.len(), 1);
+            assert!(stepped[0].value() > last);
+            last = stepped[0].value();
+        }
+        assert_eq!(last, 32000);
+        assert!(at - now <= SMOOTHING_STEP * 5);
+    }
+
+    #[test]
+    fn smoothing_never_stalls() {
+        assert_eq!(smoothing_step(0, 100, 0, 0.0), 1);
+        assert_eq!(smoothing_step(0, -100, 0, 0.001), -1);
+        assert_eq!(smoothing_step(90, 100, 10, 0.5), 100);
+    }
+
+    #[test]
+    fn flipped_up_left_points_down_right() {
+        let config = Config::default();
+        let flipped = |events: &[InputEvent]| -> Vec<(u16, i32)> {
+            events.iter().map(|ev| flip(*ev, &config)).map(|ev| (ev.code(), ev.value())).collect()
+        };
+        let (min, max) = config.analog_range();
+        assert_eq!(flipped(&[abs(AbsoluteAxisType::ABS_X, min), abs(AbsoluteAxisType::ABS_Y, min)]),
+            [(AbsoluteAxisType::ABS_X.0, max), (AbsoluteAxisType::ABS_Y.0, max)]);
+        assert_eq!(flipped(&[abs(AbsoluteAxisType::ABS_HAT0X, -1), abs(AbsoluteAxisType::ABS_HAT0Y, -1)]),
+            [(AbsoluteAxisType::ABS_HAT0X.0, 1), (AbsoluteAxisType::ABS_HAT0Y.0, 1)]);
+        assert_eq!(flipped(&[key(Key::BTN_DPAD_UP, 1), key(Key::BTN_DPAD_LEFT, 1)]),
+            [(Key::BTN_DPAD_DOWN.0, 1), (Key::BTN_DPAD_RIGHT.0, 1)]);
+        // centered stays centered, and left and right swap places
+        assert_eq!(flipped(&[abs(AbsoluteAxisType::ABS_RX, 0), abs(AbsoluteAxisType::ABS_Z, 100), key(Key::BTN_TL, 1)]),
+            [(AbsoluteAxisType::ABS_RX.0, 0), (AbsoluteAxisType::ABS_RZ.0, 100), (Key::BTN_TR.0, 1)]);
+    }
+
+    #[test]
+    fn select_turns_the_dpad_into_the_right_stick() {
+        let mut dpad = DpadStick::new(Key::BTN_SELECT);
+        let up = abs(AbsoluteAxisType::ABS_HAT0Y, -1);
+        assert_eq!(values(&dpad.apply(up)), [(AbsoluteAxisType::ABS_HAT0Y.0, -1)]);
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0Y, 0))), [(AbsoluteAxisType::ABS_HAT0Y.0, 0)]);
+
+        assert!(dpad.apply(key(Key::BTN_SELECT, 1)).is_empty());
+        assert_eq!(values(&dpad.apply(up)), [(AbsoluteAxisType::ABS_RY.0, -crate::MAX_OUT_ANALOG)]);
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0X, 1))), [(AbsoluteAxisType::ABS_RX.0, crate::MAX_OUT_ANALOG)]);
+        // letting go of select recenters whatever is still deflected, and select itself never goes out
+        let mut released = values(&dpad.apply(key(Key::BTN_SELECT, 0)));
+        released.sort();
+        assert_eq!(released, [(AbsoluteAxisType::ABS_RX.0, 0), (AbsoluteAxisType::ABS_RY.0, 0)]);
+        assert_eq!(values(&dpad.apply(up)), [(AbsoluteAxisType::ABS_HAT0Y.0, -1)]);
+    }
+
+    #[test]
+    fn dpad_pressed_before_select_is_released_as_a_hat() {
+        let mut dpad = DpadStick::new(Key::BTN_SELECT);
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0X, 1))), [(AbsoluteAxisType::ABS_HAT0X.0, 1)]);
+        assert!(dpad.apply(key(Key::BTN_SELECT, 1)).is_empty());
+        // still held from before, so it stays a hat until let go
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0X, -1))), [(AbsoluteAxisType::ABS_HAT0X.0, -1)]);
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0X, 0))), [(AbsoluteAxisType::ABS_HAT0X.0, 0)]);
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0X, 1))), [(AbsoluteAxisType::ABS_RX.0, crate::MAX_OUT_ANALOG)]);
+    }
+
+    #[test]
+    fn releasing_select_first_recenters_the_stick() {
+        let mut dpad = DpadStick::new(Key::BTN_SELECT);
+        dpad.apply(key(Key::BTN_SELECT, 1));
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0Y, 1))), [(AbsoluteAxisType::ABS_RY.0, crate::MAX_OUT_ANALOG)]);
+        assert_eq!(values(&dpad.apply(key(Key::BTN_SELECT, 0))), [(AbsoluteAxisType::ABS_RY.0, 0)]);
+        // the dpad is back to being one, the stick isn't deflected again
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0Y, -1))), [(AbsoluteAxisType::ABS_HAT0Y.0, -1)]);
+        assert_eq!(values(&dpad.apply(abs(AbsoluteAxisType::ABS_HAT0Y, 0))), [(AbsoluteAxisType::ABS_HAT0Y.0, 0)]);
+    }
+
+    #[test]
+    fn tapping_select_alone_still_selects() {
+        let mut dpad = DpadStick::new(Key::BTN_SELECT);
+        assert!(dpad.apply(key(Key::BTN_SELECT, 1)).is_empty());
+        assert_eq!(values(&dpad.apply(key(Key::BTN_SELECT, 0))), [(Key::BTN_SELECT.0, 1), (Key::BTN_SELECT.0, 0)]);
+    }
+
+    #[test]
+    fn deadzone_is_radial_and_rescaled() {
+        let mut deadzone = Deadzone::new(0.25, &HashSet::new());
+        let full = crate::MAX_OUT_ANALOG;
+        // well inside, on either axis or both
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_X, full / 5))), [(AbsoluteAxisType::ABS_X.0, 0), (AbsoluteAxisType::ABS_Y.0, 0)]);
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_Y, full / 8))), [(AbsoluteAxisType::ABS_X.0, 0), (AbsoluteAxisType::ABS_Y.0, 0)]);
+        // both together make it past the edge, which a square deadzone wouldn't tell
+        let out = deadzone.apply(abs(AbsoluteAxisType::ABS_Y, full / 5));
+        assert!(out.iter().all(|ev| ev.value() > 0));
+
+        // stretched from just past the edge, full deflection stays full
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_Y, 0))), [(AbsoluteAxisType::ABS_X.0, 0), (AbsoluteAxisType::ABS_Y.0, 0)]);
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_X, full / 4 + 100))), [(AbsoluteAxisType::ABS_X.0, 132), (AbsoluteAxisType::ABS_Y.0, 0)]);
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_X, -full - 1))), [(AbsoluteAxisType::ABS_X.0, -full), (AbsoluteAxisType::ABS_Y.0, 0)]);
+        // triggers and hats go through untouched
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_Z, 3))), [(AbsoluteAxisType::ABS_Z.0, 3)]);
+    }
+
+    #[test]
+    fn sticks_with_a_raw_axis_have_no_deadzone() {
+        let mut deadzone = Deadzone::new(0.25, &HashSet::from([AbsoluteAxisType::ABS_RY.0]));
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_RX, 100))), [(AbsoluteAxisType::ABS_RX.0, 100)]);
+        assert_eq!(values(&deadzone.apply(abs(AbsoluteAxisType::ABS_X, 100))), [(AbsoluteAxisType::ABS_X.0, 0), (AbsoluteAxisType::ABS_Y.0, 0)]);
+        let mut off = Deadzone::new(0.0, &HashSet::new());
+        assert_eq!(values(&off.apply(abs(AbsoluteAxisType::ABS_X, 100))), [(AbsoluteAxisType::ABS_X.0, 100)]);
+    }
+
+    #[test]
+    fn inverting_keeps_center_and_flips_extremes() {
+        let config = Config::default();
+        let (min, max) = config.analog_range();
+        assert_eq!(invert_axis(AbsoluteAxisType::ABS_RY, 0, &config), 0);
+        assert_eq!(invert_axis(AbsoluteAxisType::ABS_RY, min, &config), max);
+        assert_eq!(invert_axis(AbsoluteAxisType::ABS_RY, max, &config), -max);
+        assert_eq!(invert_axis(AbsoluteAxisType::ABS_HAT0X, -1, &config), 1);
+        // triggers have no center, resting becomes full pull
+        let (rest, full) = crate::output_range(AbsoluteAxisType::ABS_Z, &config);
+        assert_eq!(invert_axis(AbsoluteAxisType::ABS_Z, rest, &config), full);
+        assert_eq!(invert_axis(AbsoluteAxisType::ABS_Z, full, &config), rest);
+    }
+
+    #[test]
+    fn inverted_axes_and_swapped_sticks() {
+        let config = Config::default();
+        let device = DeviceConfig { invert: vec!["ABS_RY".into()], swap_sticks: true, ..Default::default() };
+        let fix = AxisFix::new(Some(&device));
+        let fixed = |ev| values(&[fix.apply(ev, &config)]);
+        assert_eq!(fixed(abs(AbsoluteAxisType::ABS_RY, 1000)), [(AbsoluteAxisType::ABS_Y.0, -1000)]);
+        assert_eq!(fixed(abs(AbsoluteAxisType::ABS_RY, 0)), [(AbsoluteAxisType::ABS_Y.0, 0)]);
+        assert_eq!(fixed(abs(AbsoluteAxisType::ABS_Y, 1000)), [(AbsoluteAxisType::ABS_RY.0, 1000)]);
+        assert_eq!(fixed(key(Key::BTN_SOUTH, 1)), [(Key::BTN_SOUTH.0, 1)]);
+        // without a device config nothing changes
+        let none = AxisFix::new(None);
+        assert_eq!(values(&[none.apply(abs(AbsoluteAxisType::ABS_RY, 1000), &config)]), [(AbsoluteAxisType::ABS_RY.0, 1000)]);
+    }
+}